@@ -27,10 +27,18 @@ pub enum ParseError {
     #[error("Error while parsing file")]
     InFile(#[source] Box<ParseError>),
 
+    /// A character class or range that does not make sense
+    #[error("Error at line {0}: invalid character class: {1}")]
+    InvalidClass(usize, String),
+
     /// Unexpecte token, and several hints
     #[error("Error at line {0}: unexpected: {1}, expecting one of: {2}")]
     UnexpectedHint(usize, String, String),
 
+    /// A `|` with nothing after it, the empty variants must be explicit there
+    #[error("Error at line {0}: empty variant after trailing '|', write ε for the empty string")]
+    EmptyVariant(usize),
+
     /// Error while lexing the data
     #[error("Lex error")]
     Lex(#[from] LexError),
@@ -40,25 +48,204 @@ pub enum ParseError {
 /// It may be:
 ///   - A terminal element (which is the lowest element in the tree)
 ///   - A non-terminal element (a reference to another rule)
+///   - A repetition of another atom (`?`, `*` or `+` after it)
+///   - A group of inline variants between parenthesis
+///   - A character class (or range), which matches a single character of a set
+///
 /// For instance, the rule:
 /// <fn_call> ::= <id> "(" <params> ")"
 /// Has four atoms:
 ///  * <id> Is a reference to a rule called `id` (identifier), it is a non-terminal
 ///  * "(" Is a terminal element, here the parsing dfs ends
 ///  * <params> Is a reference to another rule called `param` which may be a list of numbers, or
-///     any thing you have defined
+///    any thing you have defined
 ///  * ")" Is the last terminal element
+///
+/// The repetition operators work like in EBNF, so instead of writing a helper rule like:
+/// ```
+/// <many_digits> ::= <digit> <many_digits> | <digit>
+/// ```
+/// You can just write `<digit>+`
+///
+/// And small alternations dont need their own rule either:
+/// ```
+/// <sign> ::= ("+" | "-") <digits>
+/// ```
+///
+/// Sets of single characters are written as ranges or classes (See [`CharClass`]):
+/// ```
+/// <lowercase> ::= "a".."z"
+/// <id_char> ::= [a-zA-Z0-9_]
+/// ```
 #[derive(Debug, Clone)]
 pub enum Atom {
     Terminal { content: String },
     NonTerminal { name: String },
+    /// `atom?`, matches the atom zero or one times
+    Optional { atom: Box<Atom> },
+    /// `atom*`, matches the atom zero or more times
+    ZeroOrMore { atom: Box<Atom> },
+    /// `atom+`, matches the atom one or more times
+    OneOrMore { atom: Box<Atom> },
+    /// `( ... | ... )`, matches if any of its variants matches, just like a rule does
+    Group { variants: Vec<RuleVariant> },
+    /// `"a".."z"` or `[...]`, matches a single character of the class
+    CharClass { class: CharClass },
+}
+
+impl Atom {
+    /// Call `f` with the name of every non-terminal referenced by this atom (including the
+    /// ones inside repetitions)
+    pub fn for_each_non_terminal<'a>(&'a self, f: &mut impl FnMut(&'a str)) {
+        match self {
+            Atom::Terminal { .. } | Atom::CharClass { .. } => {}
+            Atom::NonTerminal { name } => f(name),
+            Atom::Optional { atom } | Atom::ZeroOrMore { atom } | Atom::OneOrMore { atom } => {
+                atom.for_each_non_terminal(f)
+            }
+            Atom::Group { variants } => {
+                for item in variants.iter().flat_map(|v| v.items.iter()) {
+                    item.for_each_non_terminal(f);
+                }
+            }
+        }
+    }
+
+    /// Call `f` with this atom and every atom inside of it (the repeated ones, and the ones of
+    /// the groups), the outer ones first
+    pub fn for_each_atom<'a>(&'a self, f: &mut impl FnMut(&'a Atom)) {
+        f(self);
+        match self {
+            Atom::Terminal { .. } | Atom::CharClass { .. } | Atom::NonTerminal { .. } => {}
+            Atom::Optional { atom } | Atom::ZeroOrMore { atom } | Atom::OneOrMore { atom } => {
+                atom.for_each_atom(f)
+            }
+            Atom::Group { variants } => {
+                for item in variants.iter().flat_map(|v| v.items.iter()) {
+                    item.for_each_atom(f);
+                }
+            }
+        }
+    }
+}
+
+/// The Unicode general categories (and their groups) that may be used in a class
+const CATEGORIES: &[&str] = &[
+    "L", "Lu", "Ll", "Lt", "Lm", "Lo", "M", "Mn", "Mc", "Me", "N", "Nd", "Nl", "No", "P", "Pc",
+    "Pd", "Ps", "Pe", "Pi", "Pf", "Po", "S", "Sm", "Sc", "Sk", "So", "Z", "Zs", "Zl", "Zp", "C",
+    "Cc", "Cf", "Cs", "Co", "Cn",
+];
+
+/// A set of characters, matches exactly one character
+/// It may be written as a range of terminals: `"a".."z"`, or as a class between brackets (like
+/// the ones in regular expressions):
+///  * `[abc]` Any of the three characters
+///  * `[a-zA-Z0-9_]` Any character inside of the ranges
+///  * `[^"\]` Any character except the given ones
+///  * `[\p{L}\p{Nd}]` Any letter or decimal digit, using the Unicode general categories, `\P{...}`
+///    is the complement
+///
+/// Inside the brackets, `\n`, `\t` and `\r` work like in terminals, and any other escaped
+/// character (like `\]` or `\-`) is taken literally
+///
+/// A class without any character (like `[^\p{L}\P{L}]`) is rejected, it could never match
+#[derive(Debug, Clone)]
+pub struct CharClass {
+    pub negated: bool,
+    pub items: Vec<ClassItem>,
+}
+
+/// A piece of a [`CharClass`]
+#[derive(Debug, Clone)]
+pub enum ClassItem {
+    /// An inclusive range of characters (a single character is the range `c..c`)
+    Range { from: char, to: char },
+    /// A Unicode general category (like `L` or `Nd`), or its complement
+    Category { name: String, negated: bool },
+}
+
+impl CharClass {
+    /// Check if the character belongs to this class
+    pub fn contains(&self, c: char) -> bool {
+        let found = self.items.iter().any(|item| match item {
+            ClassItem::Range { from, to } => *from <= c && c <= *to,
+            ClassItem::Category { name, negated } => {
+                let cat = unicode_general_category::get_general_category(c).abbreviation();
+                cat.starts_with(name.as_str()) != *negated
+            }
+        });
+
+        found != self.negated
+    }
+
+    /// The characters of the class, in order (and each one once)
+    pub fn chars(&self) -> Box<dyn Iterator<Item = char> + '_> {
+        // Only ranges, no need to look at every character
+        let ranges: Option<Vec<(char, char)>> = (!self.negated)
+            .then(|| {
+                let items = self.items.iter();
+                items
+                    .map(|item| match item {
+                        ClassItem::Range { from, to } => Some((*from, *to)),
+                        ClassItem::Category { .. } => None,
+                    })
+                    .collect()
+            })
+            .flatten();
+
+        let Some(mut ranges) = ranges else {
+            return Box::new((char::MIN..=char::MAX).filter(|c| self.contains(*c)));
+        };
+
+        // Merge the ones that overlap, so no character is repeated
+        ranges.sort();
+        let mut merged: Vec<(char, char)> = Vec::new();
+        for (from, to) in ranges {
+            match merged.last_mut() {
+                Some(last) if from <= last.1 => last.1 = last.1.max(to),
+                _ => merged.push((from, to)),
+            }
+        }
+        Box::new(merged.into_iter().flat_map(|(from, to)| from..=to))
+    }
+}
+
+/// Write the class back in the syntax of the grammar files (like `[^a-z\p{Nd}]`)
+impl std::fmt::Display for CharClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let escaped = |c: char| match c {
+            '\n' => "\\n".to_string(),
+            '\t' => "\\t".to_string(),
+            '\r' => "\\r".to_string(),
+            '\\' | ']' | '-' | '^' => format!("\\{c}"),
+            c => c.to_string(),
+        };
+
+        f.write_str(if self.negated { "[^" } else { "[" })?;
+        for item in self.items.iter() {
+            match item {
+                ClassItem::Range { from, to } if from == to => f.write_str(&escaped(*from))?,
+                ClassItem::Range { from, to } => {
+                    write!(f, "{}-{}", escaped(*from), escaped(*to))?;
+                }
+                ClassItem::Category { name, negated } => {
+                    write!(f, "\\{}{{{name}}}", if *negated { 'P' } else { 'p' })?;
+                }
+            }
+        }
+        f.write_str("]")
+    }
 }
 
 /// It is a set of terminals and non-terminals that a rule may match
 /// More information in [`Rule`]'s docs
 #[derive(Debug, Clone)]
 pub struct RuleVariant {
+    /// The atoms, one after the other, no atoms means the empty string
     pub items: Vec<Atom>,
+    /// How likely is this variant to be chosen when generating, relative to the other ones
+    /// (by default 1), set with `@N` after the variant
+    pub weight: u32,
 }
 
 /// A rule is a set of terminals and non-terminals, usually grouped into variants
@@ -102,9 +289,31 @@ pub struct RuleVariant {
 ///
 /// <date> ::= <number> "/" <number> "/" <date>
 /// ```
+///
+/// A rule may be documented with `///` comments right before it, those are kept in
+/// [`Rule::doc`] (one line per comment line):
+/// ```
+/// /// A date like 10/5/2080
+/// <date> ::= <number> "/" <number> "/" <number>
+/// ```
+///
+/// A variant may be empty (matching the empty string), with an explicit `ε`, or with nothing
+/// at all before another `|` (a `|` at the end of a line or a group is an error):
+/// ```
+/// <sign> ::= "+" | "-" | ε
+/// <sign> ::= | "+" | "-"
+/// ```
+///
+/// A variant may end with a weight (`@N`, by default 1), when generating random strings it is
+/// chosen N times more often than a variant with weight 1 (a weight of 0 means never, unless
+/// all of them are 0):
+/// ```
+/// <digit> ::= "0" @3 | <non_zero_digit> @1
+/// ```
 #[derive(Debug, Clone)]
 pub struct Rule {
     pub name: String,
+    pub doc: Option<String>,
     pub variants: Vec<RuleVariant>,
 }
 
@@ -115,23 +324,36 @@ use crate::lex::{tokenize, LexError, Tk};
 pub fn parse(data: &str) -> ParseResult {
     let data = tokenize(data)?;
     // Now parse
-    Parser::parse(&data)
+    Parser::parse(&data.tks, &data.lines)
 }
 
 /// To make our life easier
 struct Parser<'a, 'b> {
     data: &'a [Tk<'b>],
-    lineno: usize,
+    /// The line of every token, from the first one (not only the ones left in `data`)
+    lines: &'a [usize],
+    /// The doc comments found since the last rule
+    docs: Vec<&'b str>,
 }
 
 impl<'a, 'b> Parser<'a, 'b> {
+    /// The line of the next token (or of the last one, at the end of the input)
+    fn lineno(&self) -> usize {
+        let idx = self.lines.len() - self.data.len();
+        self.lines
+            .get(idx)
+            .or(self.lines.last())
+            .copied()
+            .unwrap_or(1)
+    }
+
     // To remove some boilerplate
     fn unexpected(&self, expecting: &str) -> ParseError {
         let tk = match self.data {
             [unex, ..] => format!("{unex:?}"),
             [] => "EOF".into(),
         };
-        ParseError::UnexpectedHint(self.lineno, tk, expecting.into())
+        ParseError::UnexpectedHint(self.lineno(), tk, expecting.into())
     }
 
     fn un_escape(&self, data: &str) -> String {
@@ -146,8 +368,6 @@ impl<'a, 'b> Parser<'a, 'b> {
                     out.push('\t');
                 }else if c=='r' {
                     out.push('\r');
-                }else if c=='r' {
-                    out.push('\r');
                 }else{
                     out.push(c);
                 }
@@ -161,19 +381,53 @@ impl<'a, 'b> Parser<'a, 'b> {
         out
     }
 
-    /// Pop an atom from the input data
+    /// Pop an atom from the input data, including its repetition operator (if any)
     /// See [`Atom`]
     fn reduce_atom(&mut self) -> Result<Atom, ParseError> {
+        let mut atom = self.reduce_primary()?;
+
+        // The postfix operators, they may be stacked (like `<a>+?`)
+        loop {
+            atom = match self.data {
+                [Tk::Question, ..] => Atom::Optional { atom: atom.into() },
+                [Tk::Star, ..] => Atom::ZeroOrMore { atom: atom.into() },
+                [Tk::Plus, ..] => Atom::OneOrMore { atom: atom.into() },
+                _ => return Ok(atom),
+            };
+            self.data = &self.data[1..];
+        }
+    }
+
+    /// Pop a terminal, non-terminal or group from the input data (without the repetition
+    /// operators)
+    /// See [`Atom`]
+    fn reduce_primary(&mut self) -> Result<Atom, ParseError> {
         // Check if is a terminal or not
         match self.data {
             [Tk::Lt, ..] => self.data = &self.data[1..],
+            [Tk::LParen, ..] => {
+                self.data = &self.data[1..];
+                return self.reduce_group();
+            }
+            [Tk::Terminal(from), Tk::DotDot, Tk::Terminal(to), ..] => {
+                self.data = &self.data[3..];
+                return self.reduce_range(from, to);
+            }
             [Tk::Terminal(term), ..] => {
                 self.data = &self.data[1..];
                 return Ok(Atom::Terminal {
                     content: self.un_escape(term),
                 });
             }
-            _ => return Err(ParseError::InAtom(self.unexpected("'<' or \"...\"").into())),
+            [Tk::Class(class), ..] => {
+                self.data = &self.data[1..];
+                return self.reduce_class(class);
+            }
+            _ => {
+                return Err(ParseError::InAtom(
+                    self.unexpected("'<', '(', '[' or \"...\"").into(),
+                ))
+            }
         }
 
         // Parse the non-terminal
@@ -198,7 +452,138 @@ impl<'a, 'b> Parser<'a, 'b> {
             _ => return Err(ParseError::InAtom(self.unexpected("'>'").into())),
         }
 
-        return Ok(Atom::NonTerminal { name });
+        Ok(Atom::NonTerminal { name })
+    }
+
+    /// Build a class from a range of two terminals (already popped)
+    /// See [`CharClass`]
+    fn reduce_range(&self, from: &str, to: &str) -> Result<Atom, ParseError> {
+        let single = |term: &str| {
+            let term = self.un_escape(term);
+            let mut chars = term.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(c),
+                _ => Err(ParseError::InvalidClass(
+                    self.lineno(),
+                    format!("the ends of a range must be single characters, found: {term:?}"),
+                )),
+            }
+        };
+        let (from, to) = (single(from)?, single(to)?);
+
+        if from > to {
+            return Err(ParseError::InvalidClass(
+                self.lineno(),
+                format!("the range {from:?}..{to:?} is empty"),
+            ));
+        }
+
+        Ok(Atom::CharClass {
+            class: CharClass {
+                negated: false,
+                items: vec![ClassItem::Range { from, to }],
+            },
+        })
+    }
+
+    /// Parse the inside of a class (already popped, without the brackets)
+    /// See [`CharClass`]
+    fn reduce_class(&self, data: &str) -> Result<Atom, ParseError> {
+        let invalid = |msg: String| ParseError::InvalidClass(self.lineno(), msg);
+
+        let (negated, data) = match data.strip_prefix('^') {
+            Some(data) => (true, data),
+            None => (false, data),
+        };
+
+        // First get the characters (with escapes resolved) and the categories
+        // A `true` means that the character was escaped (so, it cannot be a range dash)
+        let mut chars = Vec::new();
+        let mut items = Vec::new();
+        let mut it = data.chars();
+        while let Some(c) = it.next() {
+            if c != '\\' {
+                chars.push((c, false));
+                continue;
+            }
+
+            match it.next() {
+                Some(p @ ('p' | 'P')) => {
+                    let rest = it.as_str();
+                    let name = rest
+                        .strip_prefix('{')
+                        .and_then(|rest| rest.split_once('}'))
+                        .map(|(name, _)| name)
+                        .ok_or_else(|| invalid(format!("expected '{{' after \\{p}")))?;
+                    if !CATEGORIES.contains(&name) {
+                        return Err(invalid(format!("unknown Unicode category: {name}")));
+                    }
+                    it = rest[name.len() + 2..].chars();
+                    items.push(ClassItem::Category {
+                        name: name.into(),
+                        negated: p == 'P',
+                    });
+                }
+                Some('n') => chars.push(('\n', true)),
+                Some('t') => chars.push(('\t', true)),
+                Some('r') => chars.push(('\r', true)),
+                Some(c) => chars.push((c, true)),
+                None => return Err(invalid("the class ends with a '\\'".into())),
+            }
+        }
+
+        // Now the ranges
+        let mut chars = &chars[..];
+        loop {
+            match chars {
+                [(from, _), ('-', false), (to, _), ..] => {
+                    if from > to {
+                        return Err(invalid(format!("the range {from:?}-{to:?} is empty")));
+                    }
+                    items.push(ClassItem::Range {
+                        from: *from,
+                        to: *to,
+                    });
+                    chars = &chars[3..];
+                }
+                [(c, _), ..] => {
+                    items.push(ClassItem::Range { from: *c, to: *c });
+                    chars = &chars[1..];
+                }
+                [] => break,
+            }
+        }
+
+        if items.is_empty() {
+            return Err(invalid("the class is empty".into()));
+        }
+
+        // Like `[^\p{L}\P{L}]`, nothing could be generated for it
+        let class = CharClass { negated, items };
+        if class.chars().next().is_none() {
+            return Err(invalid("the class has no characters".into()));
+        }
+
+        Ok(Atom::CharClass { class })
+    }
+
+    /// Pop the variants of a group, the opening '(' was already popped
+    /// See [`Atom`]
+    fn reduce_group(&mut self) -> Result<Atom, ParseError> {
+        let mut variants = Vec::new();
+        self.reduce_variants(&mut variants)
+            .map_err(|e| ParseError::InAtom(e.into()))?;
+
+        // The closing )
+        match self.data {
+            [Tk::RParen, ..] => {
+                self.data = &self.data[1..];
+                // Ok
+            }
+            _ => return Err(ParseError::InAtom(self.unexpected("')' or '|'").into())),
+        }
+
+        Ok(Atom::Group { variants })
     }
 
     /// Pop a variant from the input data
@@ -212,8 +597,22 @@ impl<'a, 'b> Parser<'a, 'b> {
 
         // Check if more
         match self.data {
-            [Tk::Lt, ..] | [Tk::Terminal(_), ..] => self.reduce_variant(idx, vari),
-            _ => Ok(()),
+            [Tk::Lt, ..] | [Tk::LParen, ..] | [Tk::Terminal(_), ..] | [Tk::Class(_), ..] => {
+                self.reduce_variant(idx, vari)
+            }
+            _ => {
+                self.reduce_weight(vari);
+                Ok(())
+            }
+        }
+    }
+
+    /// Pop the weight that ends a variant, if any
+    /// See: [`RuleVariant`]
+    fn reduce_weight(&mut self, vari: &mut RuleVariant) {
+        if let [Tk::Weight(weight), ..] = self.data {
+            vari.weight = *weight;
+            self.data = &self.data[1..];
         }
     }
 
@@ -221,20 +620,38 @@ impl<'a, 'b> Parser<'a, 'b> {
     /// See: [`Rule`], [`RuleVariant`]
     fn reduce_variants(&mut self, outp: &mut Vec<RuleVariant>) -> Result<(), ParseError> {
         // Pop the variant
-        let mut variant = RuleVariant { items: Vec::new() };
-        self.reduce_variant(outp.len() + 1, &mut variant)?;
+        let mut variant = RuleVariant {
+            items: Vec::new(),
+            weight: 1,
+        };
+        match self.data {
+            // An explicit empty variant
+            [Tk::Epsilon, ..] => {
+                self.data = &self.data[1..];
+                self.reduce_weight(&mut variant);
+            }
+            // Nothing before the next variant, it is empty too
+            [Tk::Pipe, ..] => {}
+            // Nothing after the previous variant, probably a mistake
+            [] | [Tk::Nl | Tk::RParen | Tk::Weight(_), ..] if !outp.is_empty() => {
+                return Err(ParseError::EmptyVariant(self.lineno()));
+            }
+            _ => self.reduce_variant(outp.len() + 1, &mut variant)?,
+        }
         outp.push(variant);
 
-        match self.data {
+        // The pipe may be in one of the next lines (skipping the blank or comment-only ones,
+        // the doc comments between the variants are just comments too)
+        let skipped = self
+            .data
+            .iter()
+            .take_while(|tk| matches!(tk, Tk::Nl | Tk::DocComment(_)))
+            .count();
+        match &self.data[skipped..] {
             [Tk::Pipe, ..] => {
-                self.data = &self.data[1..];
+                self.data = &self.data[skipped + 1..];
                 // Ok
             }
-            [Tk::Nl, Tk::Pipe, ..] => {
-                self.lineno += 1;
-                self.data = &self.data[2..];
-                // Also Ok
-            }
             _ => {
                 return Ok(());
             }
@@ -248,7 +665,7 @@ impl<'a, 'b> Parser<'a, 'b> {
     fn reduce_rule(&mut self, prev: Option<&str>) -> Result<Rule, ParseError> {
         let noname_msg = match prev {
             Some(name) => format!("(name not reached, previous was: {name}"),
-            Option::None => format!("(name not reached, it is the first)"),
+            Option::None => "(name not reached, it is the first)".to_string(),
         };
 
         // The <
@@ -304,24 +721,35 @@ impl<'a, 'b> Parser<'a, 'b> {
             .map_err(|e| ParseError::InRule(name.clone(), e.into()))?;
 
         // Done
-        Ok(Rule { name, variants })
+        let doc = if self.docs.is_empty() {
+            None
+        } else {
+            Some(self.docs.join("\n"))
+        };
+        self.docs.clear();
+
+        Ok(Rule {
+            name,
+            doc,
+            variants,
+        })
     }
 
     /// Pop all the rules in the haystack
     fn reduce_rules(&mut self, rules: &mut Vec<Rule>) -> Result<(), ParseError> {
         match self.data {
             [Tk::Nl, ..] => {
-                self.lineno += 1;
+                self.data = &self.data[1..];
+                self.reduce_rules(rules)
+            }
+            [Tk::DocComment(doc), ..] => {
+                self.docs.push(doc);
                 self.data = &self.data[1..];
                 self.reduce_rules(rules)
             }
             [Tk::Lt, ..] => {
                 // Previous name
-                let prev: Option<&str> = if rules.len() == 0 {
-                    None
-                } else {
-                    Some(&(*rules)[0].name)
-                };
+                let prev: Option<&str> = rules.first().map(|r| r.name.as_str());
 
                 let res = self.reduce_rule(prev)?;
                 rules.push(res);
@@ -342,11 +770,30 @@ impl<'a, 'b> Parser<'a, 'b> {
     ///
     /// <non-terminal> ::= "<" ID ">"
     ///
-    /// <atom> ::= <terminal>
-    ///         | <non-terminal>
+    /// <group> ::= "(" <rule-variants> ")"
+    ///
+    /// <class> ::= CLASS
+    ///          | QUOTED_TEXT ".." QUOTED_TEXT
+    ///
+    /// <primary> ::= <terminal>
+    ///            | <non-terminal>
+    ///            | <group>
+    ///            | <class>
+    ///
+    /// <atom> ::= <primary>
+    ///         | <atom> "?"
+    ///         | <atom> "*"
+    ///         | <atom> "+"
+    ///
+    /// <atoms> ::= <atom> <atoms>
+    ///          | <atom>
     ///
-    /// <rule-variant>  ::= atom <rule-variant>
-    ///                  | atom
+    /// <body> ::= <atoms>
+    ///         | "ε"
+    ///         | (nothing, only before another "|")
+    ///
+    /// <rule-variant> ::= <body> WEIGHT
+    ///                 | <body>
     ///
     /// <rule-variants> ::= <rule-variant> "|" <rule-variants>
     ///                  |  <rule-variant>
@@ -356,9 +803,112 @@ impl<'a, 'b> Parser<'a, 'b> {
     /// <rules> ::= <rule> <NL> <rule>
     ///          | <rule>
     ///
-    fn parse(data: &[Tk<'b>]) -> Result<Vec<Rule>, ParseError> {
+    /// Comments are removed by the lexer, except for the doc comments (DOC_COMMENT) at the
+    /// start of a line, which may appear before any rule (or between the lines of its variants,
+    /// where they are ignored):
+    ///
+    /// <documented-rule> ::= DOC_COMMENT <NL> <documented-rule>
+    ///                    | <rule>
+    ///
+    fn parse(data: &[Tk<'b>], lines: &[usize]) -> Result<Vec<Rule>, ParseError> {
         let mut out = Vec::new();
-        Parser { data, lineno: 1 }.reduce_rules(&mut out)?;
+        Parser {
+            data,
+            lines,
+            docs: Vec::new(),
+        }
+        .reduce_rules(&mut out)?;
         Ok(out)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Atom};
+
+    #[test]
+    fn doc_comments_only_document_the_next_rule() {
+        let grammar = "/// The first\n\
+                       /// rule\n\
+                       <a> ::= \"x\" /// not a doc\n\
+                       /// between the variants\n\
+                       \x20    | \"y\" /* a block\n\
+                       comment */\n\
+                       <b> ::= \"z\"\n\
+                       /// The last rule\n\
+                       <c> ::= <a> | <b>\n";
+        let rules = parse(grammar).unwrap();
+        let docs: Vec<_> = rules.iter().map(|rule| rule.doc.as_deref()).collect();
+        assert_eq!(docs, [Some("The first\nrule"), None, Some("The last rule")]);
+        assert_eq!(rules[0].variants.len(), 2);
+    }
+
+    #[test]
+    fn empty_variants_need_a_pipe_after_them() {
+        let rules = parse("<a> ::= | \"x\" | | ε\n").unwrap();
+        let lens: Vec<_> = rules[0].variants.iter().map(|v| v.items.len()).collect();
+        assert_eq!(lens, [0, 1, 0, 0]);
+
+        for grammar in [
+            "<a> ::= \"b\"\n<b> ::= \"x\" |\n  \"y\"\n",
+            "<a> ::= \"b\"\n<b> ::= (\"x\" |)\n",
+        ] {
+            let err = parse(grammar).unwrap_err();
+            let mut cause: &dyn std::error::Error = &err;
+            while let Some(source) = cause.source() {
+                cause = source;
+            }
+            let cause = cause.to_string();
+            assert!(
+                cause.starts_with("Error at line 2: empty variant"),
+                "{cause}"
+            );
+        }
+    }
+
+    #[test]
+    fn block_comments_do_not_end_the_variant() {
+        let rules = parse("<a> ::= \"x\" /* multi\nline */ \"y\"\n<b> ::= <a>\n").unwrap();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].variants[0].items.len(), 2);
+
+        // The lines inside of the comment are still counted
+        let err = parse("<a> ::= \"x\" /* multi\nline */ \"y\"\n<b> ::= (\"x\" |)\n").unwrap_err();
+        let mut cause: &dyn std::error::Error = &err;
+        while let Some(source) = cause.source() {
+            cause = source;
+        }
+        let cause = cause.to_string();
+        assert!(
+            cause.starts_with("Error at line 3: empty variant"),
+            "{cause}"
+        );
+    }
+
+    #[test]
+    fn class_chars_are_sorted_and_unique() {
+        let rules = parse("<a> ::= [d-fa-eb] [^b-y]\n").unwrap();
+        let [Atom::CharClass { class }, Atom::CharClass { class: negated }] =
+            &rules[0].variants[0].items[..]
+        else {
+            unreachable!("Two classes");
+        };
+        assert_eq!(class.chars().collect::<String>(), "abcdef");
+        // Every character except the surrogates and the 24 letters
+        assert_eq!(negated.chars().take(2).collect::<String>(), "\0\u{1}");
+        assert_eq!(negated.chars().count(), 0x110000 - 0x800 - 24);
+    }
+
+    #[test]
+    fn classes_without_characters_are_rejected() {
+        let err = parse("<e> ::= \"a\" [^\u{0}-\u{10FFFF}] \"b\"\n").unwrap_err();
+        let mut cause: &dyn std::error::Error = &err;
+        while let Some(source) = cause.source() {
+            cause = source;
+        }
+        assert_eq!(
+            cause.to_string(),
+            "Error at line 1: invalid character class: the class has no characters"
+        );
+    }
+}
@@ -1,14 +1,127 @@
 //! The engine is where the BNF gets used
 //! See the [`Engine`] docs for more information
 
-use crate::ast::{Atom, Rule};
-use rand::{rngs::ThreadRng, thread_rng, Rng};
-use std::collections::{BTreeMap, BTreeSet};
+use crate::ast::{Atom, CharClass, ClassItem, Rule, RuleVariant};
+use num_bigint::BigUint;
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap};
+use std::rc::Rc;
+
+mod atoms;
+mod backtrack;
+mod count;
+mod cover;
+mod cycles;
+mod earley;
+mod enumerate;
+mod mutate;
 
 #[derive(thiserror::Error, Debug)]
 pub enum GenerateError {
     #[error("The rule {0} does not exist")]
     BadRule(String),
+
+    #[error("The rule {0} cannot generate any (finite) string")]
+    Unproductive(String),
+
+    #[error(
+        "Could not generate a string of the rule {0} with the requested length after {1} attempts"
+    )]
+    BadLength(String, usize),
+
+    #[error("Could not find a string rejected by the rule {0} after {1} mutations")]
+    NoNegative(String, usize),
+
+    #[error("The rule {0} can derive itself without adding any character, so it has infinitely many derivations")]
+    InfiniteDerivations(String),
+
+    #[error("The rule {0} has no strings of length {1}")]
+    NoStringOfLength(String, usize),
+
+    #[error("The strings of the rule {0} cannot be enumerated without a maximum length or count")]
+    Unbounded(String),
+}
+
+/// Limits for [`Engine::enumerate`], at least one of them is needed
+#[derive(Debug, Clone, Default)]
+pub struct EnumOptions {
+    /// The maximum length (in characters) of the strings
+    pub max_len: Option<usize>,
+
+    /// The maximum amount of strings
+    pub max_count: Option<usize>,
+}
+
+/// How many strings [`Engine::samples`] tries before giving up on the length limits
+const GEN_ATTEMPTS: usize = 1000;
+
+/// The default [`GenOptions::max_depth`]
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// The default [`GenOptions::max_nodes`]
+pub const DEFAULT_MAX_NODES: usize = 10000;
+
+/// Limits for the generated strings, see [`Engine::samples`]
+/// The default ones only limit the depth and the nodes (so the generation always ends)
+#[derive(Debug, Clone)]
+pub struct GenOptions {
+    /// After this many nested rules, choose the variants that need the less nesting to end,
+    /// and stop repeating or adding the optional atoms
+    pub max_depth: Option<usize>,
+
+    /// After expanding this many rules, end the string as soon as possible, like with
+    /// [`GenOptions::max_depth`], which alone does not stop the rules that branch a lot (like
+    /// `<s> ::= <s> <s> <s> | "b"`) from growing exponentially
+    pub max_nodes: Option<usize>,
+
+    /// The minimum length (in characters) of the generated strings, until the string (plus
+    /// what the rules being generated still have to add) reaches it, the generation prefers
+    /// the variants that need more nesting to end, and keeps repeating
+    pub min_len: Option<usize>,
+
+    /// The maximum length (in characters) of the generated strings, only the variants and
+    /// repetitions that keep the string (plus what the rules being generated still have to add)
+    /// within it are chosen, and once reached the generation tries to end as soon as possible,
+    /// like with [`GenOptions::max_depth`]
+    pub max_len: Option<usize>,
+}
+
+impl Default for GenOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: Some(DEFAULT_MAX_DEPTH),
+            max_nodes: Some(DEFAULT_MAX_NODES),
+            min_len: None,
+            max_len: None,
+        }
+    }
+}
+
+impl GenOptions {
+    /// Whether the generation should end as soon as possible, given the current depth and
+    /// the string generated so far
+    fn exhausted(&self, depth: usize, res: &Sample) -> bool {
+        self.max_depth.is_some_and(|max| depth >= max)
+            || self.max_nodes.is_some_and(|max| res.nodes >= max)
+            || self.max_len.is_some_and(|max| res.chars + res.owed >= max)
+    }
+
+    /// Whether a string of (at least) this length is still shorter than the minimum length
+    fn short(&self, len: usize) -> bool {
+        self.min_len.is_some_and(|min| len < min)
+    }
+
+    /// Whether a string of (at least) this length is not longer than the maximum length
+    fn room(&self, len: usize) -> bool {
+        self.max_len.is_none_or(|max| len <= max)
+    }
+
+    /// Whether the length of the string (in characters) is within the limits
+    fn fits(&self, data: &str) -> bool {
+        let len = data.chars().count();
+        self.min_len.is_none_or(|min| len >= min) && self.max_len.is_none_or(|max| len <= max)
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -19,8 +132,185 @@ pub enum MatchError {
     #[error("That Watch rule: {0} does not exist")]
     BadWatchRule(String),
 
-    #[error("No matches")]
-    NoMatches,
+    #[error(
+        "No matches, error at line {0}, column {1}: unexpected: {2}, expecting one of: {3}\n{4}"
+    )]
+    NoMatches(usize, usize, String, String, String),
+
+    #[error(
+        "The input was not matched completely, unconsumed input begins at offset {0}, near: {1:?}, expecting one of: {2}"
+    )]
+    UnconsumedInput(usize, String, String),
+}
+
+/// The matching algorithms, see [`MatchOptions`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Recursive descent, the first variant that matches is used and never revisited (like in
+    /// a PEG), fast but it may reject strings that the grammar can generate
+    #[default]
+    Descent,
+
+    /// Recursive descent with full backtracking, accepts exactly the language of the grammar
+    /// (follows the semantics of context-free grammars), but it may take exponential time
+    Backtrack,
+
+    /// Earley parser, accepts exactly the language of the grammar (just like
+    /// [`Algorithm::Backtrack`]) in cubic time at most, ambiguous grammars included
+    Earley,
+}
+
+/// What each matching algorithm returns: the tree of the initial rule, or why it failed
+type MatchResult = Result<Node, Failure>;
+
+/// Why a matching algorithm failed
+#[derive(Debug, Default)]
+struct Failure {
+    /// The furthest offset where the initial rule ended (if it matched a prefix at all)
+    longest: Option<usize>,
+    /// Where the input stopped matching
+    furthest: Furthest,
+}
+
+/// The furthest offset where a terminal (or a rule) failed to match, and what was expected
+/// there: the terminals (quoted), the character classes and the rules (between `<>`)
+#[derive(Debug, Default)]
+struct Furthest {
+    offset: usize,
+    expected: BTreeSet<String>,
+}
+
+impl Furthest {
+    /// Record that `what` was expected at the offset, it is ignored if something already failed
+    /// further
+    fn expect(&mut self, offset: usize, what: impl FnOnce() -> String) {
+        if offset > self.offset {
+            self.offset = offset;
+            self.expected.clear();
+        }
+
+        if offset == self.offset {
+            self.expected.insert(what());
+        }
+    }
+
+    /// Describe a terminal or character class that was expected, see [`Furthest::expect`]
+    fn atom(atom: &Atom) -> String {
+        match atom {
+            Atom::Terminal { content } => format!("{content:?}"),
+            Atom::CharClass { class } => class.to_string(),
+            Atom::NonTerminal { name } => format!("<{name}>"),
+            _ => unreachable!("Only terminals, classes and rules are expected"),
+        }
+    }
+
+    /// Build the error, with the line and column (starting at 1) of the offset, and the line
+    /// with a caret below the offending character
+    fn into_error(self, data: &str) -> MatchError {
+        let line_start = data[..self.offset].rfind('\n').map_or(0, |idx| idx + 1);
+        let line_end = data[self.offset..]
+            .find('\n')
+            .map_or(data.len(), |idx| self.offset + idx);
+        let lineno = data[..self.offset].matches('\n').count() + 1;
+        let before = &data[line_start..self.offset];
+
+        // Keep the tabs, so the caret is aligned with the character
+        let mut snippet = format!("{}\n", &data[line_start..line_end]);
+        snippet.extend(before.chars().map(|c| if c == '\t' { '\t' } else { ' ' }));
+        snippet.push('^');
+
+        let unexpected = match data[self.offset..].chars().next() {
+            Some(c) => format!("{c:?}"),
+            None => "end of input".into(),
+        };
+        let expected = self.expected.into_iter().collect::<Vec<_>>().join(", ");
+
+        MatchError::NoMatches(
+            lineno,
+            before.chars().count() + 1,
+            unexpected,
+            expected,
+            snippet,
+        )
+    }
+}
+
+/// A node of the tree returned by [`Engine::match_tree`], it is a rule that matched a slice of
+/// the input
+/// Terminals, groups and repetitions dont get their own nodes, so the children are the rules
+/// used by the variant that matched (in order)
+#[derive(Debug, Clone)]
+pub struct Node {
+    /// The name of the rule
+    pub rule: String,
+    /// The variant that matched (the index in [`Rule::variants`])
+    pub variant: usize,
+    /// The offset where the match starts
+    pub start: usize,
+    /// The offset where the match ends (exclusive)
+    pub end: usize,
+    /// Shared, so the memoized subtrees are not copied (see [`MatchOptions::packrat`])
+    pub children: Vec<Rc<Node>>,
+}
+
+impl Node {
+    /// Push the ranges of the nodes (this one and its descendants) of the watched rules, the
+    /// parents go before their children
+    fn watched(&self, to_watch: &[String], outp: &mut Vec<Match>) {
+        if to_watch.contains(&self.rule) {
+            outp.push(Match {
+                rule: self.rule.clone(),
+                start: self.start,
+                end: self.end,
+            });
+        }
+
+        for child in self.children.iter() {
+            child.watched(to_watch, outp);
+        }
+    }
+}
+
+/// The range of the input matched by one of the watched rules, see [`Engine::match_rule`]
+#[derive(Debug, Clone)]
+pub struct Match {
+    /// The name of the rule that matched
+    pub rule: String,
+
+    /// The byte offset where the match starts
+    pub start: usize,
+
+    /// The byte offset where the match ends (exclusive)
+    pub end: usize,
+}
+
+/// An occurrence of a rule inside the input, see [`Engine::search`]
+#[derive(Debug, Clone)]
+pub struct Occurrence {
+    /// The line (starting at 1) where the occurrence starts
+    pub line: usize,
+
+    /// The byte offset where the occurrence starts
+    pub start: usize,
+
+    /// The byte offset where the occurrence ends (exclusive)
+    pub end: usize,
+}
+
+/// How the matching should be done, see [`Engine::match_rule`]
+#[derive(Debug, Clone, Default)]
+pub struct MatchOptions {
+    /// The initial rule must match the whole input (anchored at both ends), by default it is
+    /// enough to match a prefix of it
+    pub full: bool,
+
+    /// The algorithm used to match
+    pub algorithm: Algorithm,
+
+    /// Remember the result of every rule at every offset, so no rule is matched twice at the
+    /// same place (packrat parsing), this makes the matching linear in time (and memory)
+    /// Only used by [`Algorithm::Descent`]
+    pub packrat: bool,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -31,127 +321,807 @@ pub enum BuildError {
     #[error("Some rules ({0}) reference inexistent non-terminals ({1})")]
     InexistentNonTerminals(String, String),
 
-    #[error("A rule ({0}) may cause an infinite recursion")]
-    InfinityRecursion(String),
+    /// A cycle of rules (from a rule back to itself) for each group of rules that never ends
+    #[error(
+        "Some rules may cause an infinite recursion: {}",
+        .0.iter().map(|cycle| cycle.join(" -> ")).collect::<Vec<_>>().join(", ")
+    )]
+    InfinityRecursion(Vec<Vec<String>>),
+}
+
+/// The results of [`Engine::match_against`] by (rule, offset), for the left-recursive rules
+/// (See [`Engine::match_left_recursive`]) and, if packrat is enabled, for every rule
+#[derive(Default)]
+struct Memo<'e> {
+    /// Save the results of all the rules, not only the left-recursive ones
+    packrat: bool,
+    /// The tree of the match, None if the rule did not match
+    results: HashMap<(&'e str, usize), Option<Rc<Node>>>,
+    /// The left-recursive rules (and their offsets) that are currently being grown
+    growing: Vec<(&'e str, usize)>,
+    /// Where the input stopped matching
+    furthest: Furthest,
+}
+
+/// The random strings of a rule, see [`Engine::samples`]
+pub struct Samples<'e, 'r, R: ?Sized> {
+    engine: &'e Engine,
+    rule: &'e Rule,
+    opts: GenOptions,
+    rng: &'r mut R,
+}
+
+impl<R: Rng + ?Sized> Iterator for Samples<'_, '_, R> {
+    type Item = Result<String, GenerateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.engine.gen_sample(self.rule, &self.opts, self.rng);
+        Some(sample.map(|sample| sample.data))
+    }
+}
+
+/// Strings of a rule with the same length, chosen uniformly, see [`Engine::uniform_samples`]
+pub struct UniformSamples<'e, 'r, R: ?Sized> {
+    counter: count::Counter<'e>,
+    rule: &'e Rule,
+    len: usize,
+    rng: &'r mut R,
+}
+
+impl<R: Rng + ?Sized> Iterator for UniformSamples<'_, '_, R> {
+    type Item = Result<String, GenerateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.counter.sample(self.rule, self.len, self.rng);
+        Some(
+            sample.ok_or_else(|| GenerateError::NoStringOfLength(self.rule.name.clone(), self.len)),
+        )
+    }
+}
+
+/// Strings close to the ones of a rule, but rejected by it, see [`Engine::negative_samples`]
+pub struct NegativeSamples<'e, 'r, R: ?Sized> {
+    mutator: mutate::Mutator<'e>,
+    opts: GenOptions,
+    rng: &'r mut R,
+}
+
+impl<R: Rng + ?Sized> Iterator for NegativeSamples<'_, '_, R> {
+    type Item = Result<String, GenerateError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.mutator.gen_negative(&self.opts, self.rng))
+    }
+}
+
+/// A generated string, with the ranges of the terminals and the rules used to generate it
+#[derive(Debug, Default)]
+struct Sample<'e> {
+    data: String,
+    /// The length of the data in characters
+    chars: usize,
+    /// How many rules were expanded so far
+    nodes: usize,
+    /// The minimum length (in characters) that the rules being generated still have to add
+    /// after the current atom, so the string will be at least `chars + owed` long
+    owed: usize,
+    /// The ranges of the terminals (and the characters of the classes), in order
+    terminals: Vec<(usize, usize)>,
+    /// The ranges of the rules (with their names), the children go before their parents
+    rules: Vec<(&'e str, usize, usize)>,
+}
+
+/// The strings generated by [`Engine::cover`]
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    /// The generated strings, together they use every variant that can be used
+    pub samples: Vec<String>,
+
+    /// The variants that no string uses, because they cannot generate any (finite) string, or
+    /// they cannot be reached without an impossible variant: the rule name, the group inside
+    /// of it (numbered from 0 in the order they are written, None for the variants of the rule
+    /// itself) and the index of the variant
+    pub uncovered: Vec<(String, Option<usize>, usize)>,
 }
 
 pub struct Engine {
     tree: BTreeMap<String, Rule>,
+    /// The rules that may call themselves without consuming any input
+    left_recursive: BTreeSet<String>,
+    /// The minimum height of the derivation trees of each rule (a rule that only uses
+    /// terminals has height 1), the rules that cannot generate any string are not here
+    min_height: BTreeMap<String, usize>,
+    /// The minimum length (in characters) of the strings of each rule, the rules that cannot
+    /// generate any string are not here either
+    min_len: BTreeMap<String, usize>,
     debug: bool,
 }
 
 impl Engine {
-    fn gen_random_variant(&self, rule: &Rule, rng: &mut ThreadRng) -> String {
-        // Choose 1 variant
-        let var: usize = rng.gen_range(0..rule.variants.len());
-        let var = &rule.variants[var];
-        let mut res = String::with_capacity(1024);
+    /// Generate a random string for the given atom, and push it to `res`
+    /// `depth` is the amount of rules being generated (the parents of this atom)
+    fn gen_random_atom<'e, R: Rng + ?Sized>(
+        &'e self,
+        atom: &Atom,
+        opts: &GenOptions,
+        depth: usize,
+        rng: &mut R,
+        res: &mut Sample<'e>,
+    ) {
+        match atom {
+            Atom::Terminal { content } => {
+                let start = res.data.len();
+                res.data += content;
+                res.chars += content.chars().count();
+                res.terminals.push((start, res.data.len()));
+            }
+            Atom::NonTerminal { name } => {
+                let (rule, start) = (&self.tree[name], res.data.len());
+                res.nodes += 1;
+                self.gen_random_variants(&rule.variants, opts, depth + 1, rng, res);
+                res.rules.push((&rule.name, start, res.data.len()));
+            }
+            Atom::Optional { atom } => {
+                if self.gen_again(atom, opts, depth, rng, res) {
+                    self.gen_random_atom(atom, opts, depth, rng, res);
+                }
+            }
+            Atom::ZeroOrMore { atom } => self.gen_repeat(atom, opts, depth, rng, res),
+            Atom::OneOrMore { atom } => {
+                self.gen_random_atom(atom, opts, depth, rng, res);
+                self.gen_repeat(atom, opts, depth, rng, res);
+            }
+            Atom::Group { variants } => {
+                self.gen_random_variants(variants, opts, depth, rng, res);
+            }
+            Atom::CharClass { class } => {
+                let start = res.data.len();
+                res.data.push(Self::gen_random_char(class, rng));
+                res.chars += 1;
+                res.terminals.push((start, res.data.len()));
+            }
+        }
+    }
 
-        for item in var.items.iter() {
-            match item {
-                Atom::Terminal { content } => {
-                    res += &content;
+    /// Whether to generate (once more) an optional or repeated atom: never if it cannot
+    /// generate anything, once the limits are exhausted or if it would make the string too
+    /// long, always while the string is too short, and otherwise half of the times (so the
+    /// repetitions follow a geometric distribution and dont get too long)
+    fn gen_again<R: Rng + ?Sized>(
+        &self,
+        atom: &Atom,
+        opts: &GenOptions,
+        depth: usize,
+        rng: &mut R,
+        res: &Sample,
+    ) -> bool {
+        let Some(min) = Self::atom_length(atom, &self.min_len) else {
+            return false;
+        };
+
+        let len = res.chars + res.owed;
+        !opts.exhausted(depth, res)
+            && opts.room(len + min)
+            && (opts.short(len) || rng.gen_bool(0.5))
+    }
+
+    /// Generate the atom zero or more times, see [`Engine::gen_again`]
+    fn gen_repeat<'e, R: Rng + ?Sized>(
+        &'e self,
+        atom: &Atom,
+        opts: &GenOptions,
+        depth: usize,
+        rng: &mut R,
+        res: &mut Sample<'e>,
+    ) {
+        while self.gen_again(atom, opts, depth, rng, res) {
+            let len = res.data.len();
+            self.gen_random_atom(atom, opts, depth, rng, res);
+
+            // It generated nothing, repeating it will not make the string any longer
+            if res.data.len() == len && opts.short(res.chars + res.owed) {
+                break;
+            }
+        }
+    }
+
+    /// Choose a random character of the class (the parser rejects the classes without any)
+    fn gen_random_char<R: Rng + ?Sized>(class: &CharClass, rng: &mut R) -> char {
+        // The simple case, only ranges, choose one of its chars directly
+        if !class.negated {
+            let ranges: Option<Vec<(u32, u32)>> = class
+                .items
+                .iter()
+                .map(|item| match item {
+                    ClassItem::Range { from, to } => Some((*from as u32, *to as u32)),
+                    ClassItem::Category { .. } => None,
+                })
+                .collect();
+
+            if let Some(ranges) = ranges {
+                let total: u32 = ranges.iter().map(|(from, to)| to - from + 1).sum();
+                let mut pick = rng.gen_range(0..total);
+                for (from, to) in ranges {
+                    if pick <= to - from {
+                        // May fail if the range contains surrogates, try the slow way then
+                        if let Some(c) = char::from_u32(from + pick) {
+                            return c;
+                        }
+                        break;
+                    }
+                    pick -= to - from + 1;
                 }
-                Atom::NonTerminal { name } => {
-                    res += &self.gen_random_variant(&self.tree[name], rng);
+            }
+        }
+
+        // Otherwise, try random characters until one belongs to the class
+        // Negated classes will usually want common characters, so try the printable ASCII first
+        if class.negated {
+            for _ in 0..1000 {
+                let c = rng.gen_range(' '..='~');
+                if class.contains(c) {
+                    return c;
                 }
             }
         }
 
-        res
+        for _ in 0..10000 {
+            let c: char = rng.gen();
+            if class.contains(c) {
+                return c;
+            }
+        }
+
+        // Very small class, look for all the members of the class, starting at a random point
+        let start: char = rng.gen();
+        (start..=char::MAX)
+            .chain(char::MIN..start)
+            .find(|c| class.contains(*c))
+            .expect("The class has some character")
+    }
+
+    /// Choose one of the variants and generate a random string for it, see
+    /// [`Engine::gen_random_atom`]
+    /// Only the variants that keep the string within the maximum length are chosen (or the
+    /// shortest ones, if none does), when the limits are exhausted only the ones with the
+    /// minimum height, and while the string is shorter than the minimum length, the other ones
+    /// (if any)
+    fn gen_random_variants<'e, R: Rng + ?Sized>(
+        &'e self,
+        variants: &[RuleVariant],
+        opts: &GenOptions,
+        depth: usize,
+        rng: &mut R,
+        res: &mut Sample<'e>,
+    ) {
+        // The variants that cannot generate anything would never end
+        let mut choices: Vec<(&RuleVariant, usize, usize)> = variants
+            .iter()
+            .filter_map(|variant| {
+                let height = Self::variant_height(variant, &self.min_height)?;
+                let len = Self::variant_length(variant, &self.min_len)?;
+                Some((variant, height, len))
+            })
+            .collect();
+
+        // The string will be at least this long, plus the length of the chosen variant
+        let len = res.chars + res.owed;
+        if let Some(shortest) = choices.iter().map(|(_, _, min)| *min).min() {
+            choices.retain(|(_, _, min)| opts.room(len + min) || *min == shortest);
+
+            let short = opts.short(len + shortest);
+            let lowest = choices.iter().map(|(_, height, _)| *height).min();
+            if opts.exhausted(depth, res) {
+                choices.retain(|(_, height, _)| Some(*height) == lowest);
+            } else if short && choices.iter().any(|(_, height, _)| Some(*height) != lowest) {
+                choices.retain(|(_, height, _)| Some(*height) != lowest);
+            }
+        }
+
+        // Choose 1 variant, using the weights (if all of them are 0, any of them)
+        let total: u64 = choices
+            .iter()
+            .map(|(variant, ..)| variant.weight as u64)
+            .sum();
+        let var = if total == 0 {
+            choices[rng.gen_range(0..choices.len())].0
+        } else {
+            let mut pick = rng.gen_range(0..total);
+            let pos = choices.iter().position(|(variant, ..)| {
+                let found = pick < variant.weight as u64;
+                pick = pick.saturating_sub(variant.weight as u64);
+                found
+            });
+            choices[pos.expect("The pick is lower than the total")].0
+        };
+
+        // The atoms after each one still have to add their minimum length
+        let mut rest = Self::variant_length(var, &self.min_len).expect("It can generate strings");
+        for item in var.items.iter() {
+            rest -= Self::atom_length(item, &self.min_len).expect("It can generate strings");
+            res.owed += rest;
+            self.gen_random_atom(item, opts, depth, rng, res);
+            res.owed -= rest;
+        }
     }
 
-    /// Generate a new random (valid) string
-    pub fn gen_random(&self, rule: &str) -> Result<String, GenerateError> {
-        let mut rng = thread_rng();
+    /// Generate a new random (valid) string, with the default [`GenOptions`]
+    /// A shorthand for the first of [`Engine::samples`]
+    #[allow(dead_code)] // The binary uses `samples`, this is kept for the simple cases
+    pub fn gen_random<R: Rng + ?Sized>(
+        &self,
+        rule: &str,
+        rng: &mut R,
+    ) -> Result<String, GenerateError> {
+        let mut samples = self.samples(rule, &GenOptions::default(), rng)?;
+        samples.next().expect("The samples never end")
+    }
 
+    /// Generate random (valid) strings of the rule, the returned iterator never ends
+    /// The given random number generator is used for all of them (a seeded one makes the
+    /// output reproducible)
+    pub fn samples<'e, 'r, R: Rng + ?Sized>(
+        &'e self,
+        rule: &str,
+        opts: &GenOptions,
+        rng: &'r mut R,
+    ) -> Result<Samples<'e, 'r, R>, GenerateError> {
         let Some(rule) = self.tree.get(rule) else {
             return Err(GenerateError::BadRule(rule.into()));
         };
 
-        Ok(self.gen_random_variant(rule, &mut rng))
+        if !self.min_height.contains_key(&rule.name) {
+            return Err(GenerateError::Unproductive(rule.name.clone()));
+        }
+
+        Ok(Samples {
+            engine: self,
+            rule,
+            opts: opts.clone(),
+            rng,
+        })
     }
 
-    /// Match against the given rule (and optionally save the range)
+    /// Generate random strings of the rule with exactly the given length (in characters), every
+    /// derivation of that length is equally likely, see [`count::Counter`]
+    /// It is not uniform over the strings if the rule is ambiguous (see
+    /// [`Engine::count_derivations`]), the ones with more derivations are more likely
+    pub fn uniform_samples<'e, 'r, R: Rng + ?Sized>(
+        &'e self,
+        rule: &str,
+        len: usize,
+        rng: &'r mut R,
+    ) -> Result<UniformSamples<'e, 'r, R>, GenerateError> {
+        let Some(rule) = self.tree.get(rule) else {
+            return Err(GenerateError::BadRule(rule.into()));
+        };
+
+        let counter = count::Counter::new(self, rule, len)?;
+        if counter.rule(&rule.name)[len] == 0u32.into() {
+            return Err(GenerateError::NoStringOfLength(rule.name.clone(), len));
+        }
+
+        Ok(UniformSamples {
+            counter,
+            rule,
+            len,
+            rng,
+        })
+    }
+
+    /// Count how many derivations of each length (in characters, the index) the rule has, from
+    /// 0 up to `max_len`, see [`count::Counter`]
+    /// If there are more derivations than distinct strings (see [`Engine::count_strings`]),
+    /// the grammar is ambiguous
+    pub fn count_derivations(
+        &self,
+        rule: &str,
+        max_len: usize,
+    ) -> Result<Vec<BigUint>, GenerateError> {
+        let Some(rule) = self.tree.get(rule) else {
+            return Err(GenerateError::BadRule(rule.into()));
+        };
+
+        let counter = count::Counter::new(self, rule, max_len)?;
+        Ok(counter.rule(&rule.name).clone())
+    }
+
+    /// Count how many distinct strings of each length (in characters, the index) the rule has,
+    /// from 0 up to `max_len`
+    /// All of them are enumerated (see [`Engine::enumerate`]), so this is much slower than
+    /// [`Engine::count_derivations`]
+    pub fn count_strings(&self, rule: &str, max_len: usize) -> Result<Vec<usize>, GenerateError> {
+        let opts = EnumOptions {
+            max_len: Some(max_len),
+            max_count: None,
+        };
+
+        let mut counts = vec![0; max_len + 1];
+        for data in self.enumerate(rule, &opts)? {
+            counts[data.chars().count()] += 1;
+        }
+        Ok(counts)
+    }
+
+    /// Generate strings that are almost like the ones of the rule, but rejected by it (when
+    /// matching the whole input), by mutating the generated ones, see [`mutate::Mutator`]
+    pub fn negative_samples<'e, 'r, R: Rng + ?Sized>(
+        &'e self,
+        rule: &str,
+        opts: &GenOptions,
+        rng: &'r mut R,
+    ) -> Result<NegativeSamples<'e, 'r, R>, GenerateError> {
+        let Some(rule) = self.tree.get(rule) else {
+            return Err(GenerateError::BadRule(rule.into()));
+        };
+
+        if !self.min_height.contains_key(&rule.name) {
+            return Err(GenerateError::Unproductive(rule.name.clone()));
+        }
+
+        Ok(NegativeSamples {
+            mutator: mutate::Mutator::new(self, rule),
+            opts: opts.clone(),
+            rng,
+        })
+    }
+
+    /// Generate a small set of strings that, together, use every variant of every rule that
+    /// can be reached from the given one, see [`cover::Cover`]
+    pub fn cover<R: Rng + ?Sized>(
+        &self,
+        rule: &str,
+        rng: &mut R,
+    ) -> Result<Coverage, GenerateError> {
+        let Some(rule) = self.tree.get(rule) else {
+            return Err(GenerateError::BadRule(rule.into()));
+        };
+
+        if !self.min_height.contains_key(&rule.name) {
+            return Err(GenerateError::Unproductive(rule.name.clone()));
+        }
+
+        Ok(cover::Cover::new(self, rule, rng).run(rule))
+    }
+
+    /// List every distinct string of the rule, in shortlex order (the shorter first, and the
+    /// ones with the same length in lexicographic order), up to the given limits
+    pub fn enumerate(&self, rule: &str, opts: &EnumOptions) -> Result<Vec<String>, GenerateError> {
+        let Some(rule) = self.tree.get(rule) else {
+            return Err(GenerateError::BadRule(rule.into()));
+        };
+
+        if opts.max_len.is_none() && opts.max_count.is_none() {
+            return Err(GenerateError::Unbounded(rule.name.clone()));
+        }
+
+        Ok(enumerate::Enumerator::new(self, rule, opts).run())
+    }
+
+    /// Generate a single string of the rule, if it does not fit the length limits another one
+    /// is generated, up to [`GEN_ATTEMPTS`] times
+    fn gen_sample<'e, R: Rng + ?Sized>(
+        &'e self,
+        rule: &'e Rule,
+        opts: &GenOptions,
+        rng: &mut R,
+    ) -> Result<Sample<'e>, GenerateError> {
+        for _ in 0..GEN_ATTEMPTS {
+            let mut res = Sample::default();
+            self.gen_random_variants(&rule.variants, opts, 1, rng, &mut res);
+            if opts.fits(&res.data) {
+                return Ok(res);
+            }
+        }
+
+        Err(GenerateError::BadLength(rule.name.clone(), GEN_ATTEMPTS))
+    }
+
+    /// Match against the given rule (and save its node)
     /// rule: The current rule to match against
-    /// to_watch: The rule which may be watched (saved to the vector)
     /// data: The input data (already sliced to the current offset)
     /// offset: The current offset (to add to the output vector)
     /// outp: The output vector
-    fn match_against(
-        &self,
-        rule: &Rule,
-        to_watch: &[String],
+    /// memo: The results already computed
+    fn match_against<'e>(
+        &'e self,
+        rule: &'e Rule,
         data: &str,
         offset: usize,
-        outp: &mut Vec<(usize, usize)>,
+        outp: &mut Vec<Rc<Node>>,
+        memo: &mut Memo<'e>,
     ) -> Result<usize, ()> {
         if self.debug {
             // Should probably use the `log` crate
             eprintln!(
                 "[*] Matching agains: {rule:?}, near: {:?}",
-                &data[..data.len().min(5)]
+                data.chars().take(5).collect::<String>()
             );
         }
 
+        // A left-recursive rule that is being grown (or was already)
+        if let Some(res) = memo.results.get(&(rule.name.as_str(), offset)) {
+            let node = res.clone().ok_or(())?;
+            let proc = node.end - node.start;
+            outp.push(node);
+            return Ok(proc);
+        }
+
+        let node = if self.left_recursive.contains(&rule.name) {
+            self.match_left_recursive(rule, data, offset, memo)
+        } else {
+            let res = self.match_body(rule, data, offset, memo).map(Rc::new);
+
+            // Same as in `match_left_recursive`, it may depend on a seed that is still growing
+            if memo.packrat && !memo.growing.iter().any(|(_, off)| *off == offset) {
+                memo.results
+                    .insert((rule.name.as_str(), offset), res.clone().ok());
+            }
+
+            res
+        };
+
+        let Ok(node) = node else {
+            memo.furthest.expect(offset, || format!("<{}>", rule.name));
+            return Err(());
+        };
+
+        let proc = node.end - node.start;
+        outp.push(node);
+        Ok(proc)
+    }
+
+    /// Match the variants of the rule, without looking at the memo, the arguments are the same
+    /// as in [`Engine::match_against`]
+    fn match_body<'e>(
+        &'e self,
+        rule: &'e Rule,
+        data: &str,
+        offset: usize,
+        memo: &mut Memo<'e>,
+    ) -> Result<Node, ()> {
+        let mut children = Vec::new();
+        let (variant, proc) =
+            self.match_variants(&rule.variants, data, offset, &mut children, memo)?;
+
+        if self.debug {
+            eprintln!("Done matching against {rule:?}");
+        }
+
+        Ok(Node {
+            rule: rule.name.clone(),
+            variant,
+            start: offset,
+            end: offset + proc,
+            children,
+        })
+    }
+
+    /// Match a left-recursive rule by growing a seed: first the rule is matched with the
+    /// recursive calls (at this same offset) failing, then it is matched again and again with
+    /// the recursive calls returning the previous result, until the match stops getting longer
+    /// The arguments are the same as in [`Engine::match_against`]
+    fn match_left_recursive<'e>(
+        &'e self,
+        rule: &'e Rule,
+        data: &str,
+        offset: usize,
+        memo: &mut Memo<'e>,
+    ) -> Result<Rc<Node>, ()> {
+        let key = (rule.name.as_str(), offset);
+        memo.results.insert(key, None);
+        memo.growing.push(key);
+
+        while let Ok(node) = self.match_body(rule, data, offset, memo) {
+            if let Some(Some(prev)) = memo.results.get(&key) {
+                if node.end <= prev.end {
+                    break;
+                }
+            }
+
+            if self.debug {
+                eprintln!("Left-recursive rule {} grew to {}", rule.name, node.end);
+            }
+            memo.results.insert(key, Some(Rc::new(node)));
+        }
+
+        memo.growing.pop();
+
+        // If another rule is still growing at this offset, this result may depend on its seed,
+        // so it cannot be reused
+        let res = if memo.growing.iter().any(|(_, off)| *off == offset) {
+            memo.results.remove(&key)
+        } else {
+            memo.results.get(&key).cloned()
+        };
+
+        res.flatten().ok_or(())
+    }
+
+    /// Match against the first variant (in order) that succeeds, the arguments are the same as
+    /// in [`Engine::match_against`]
+    /// Returns the index of the variant that matched and the processed length
+    /// The output vector is only modified if some variant matched
+    fn match_variants<'e>(
+        &'e self,
+        variants: &[RuleVariant],
+        data: &str,
+        offset: usize,
+        outp: &mut Vec<Rc<Node>>,
+        memo: &mut Memo<'e>,
+    ) -> Result<(usize, usize), ()> {
         let mut sub = Vec::new();
 
-        'varloop: for variant in rule.variants.iter() {
+        'varloop: for (idx, variant) in variants.iter().enumerate() {
             if self.debug {
                 eprintln!("Trying variant: {variant:?}");
             }
 
             sub.clear();
 
-            let mut data = data;
             let mut proc = 0;
 
             // Only save the values when all the atoms in the variant have succeed
             for item in variant.items.iter() {
-                match item {
-                    Atom::Terminal { content } => {
-                        if !data.starts_with(content) {
-                            if self.debug {
-                                eprintln!("Terminal {content:?} did not match near: {:?}, skipping variant", &data[..data.len().min(5)]);
-                            }
-
-                            continue 'varloop;
-                        }
+                let Ok(processed) =
+                    self.match_atom(item, &data[proc..], offset + proc, &mut sub, memo)
+                else {
+                    continue 'varloop;
+                };
+                proc += processed;
+            }
 
-                        if self.debug {
-                            eprintln!("Terminal {content:?} matched completely");
-                        }
+            outp.append(&mut sub);
+            return Ok((idx, proc));
+        }
+
+        Err(())
+    }
 
-                        data = &data[content.len()..];
-                        proc += content.len();
+    /// Match a single atom, the arguments are the same as in [`Engine::match_against`]
+    /// The output vector is only modified if the atom matched
+    fn match_atom<'e>(
+        &'e self,
+        atom: &Atom,
+        data: &str,
+        offset: usize,
+        outp: &mut Vec<Rc<Node>>,
+        memo: &mut Memo<'e>,
+    ) -> Result<usize, ()> {
+        match atom {
+            Atom::Terminal { content } => {
+                if !data.starts_with(content) {
+                    if self.debug {
+                        eprintln!(
+                            "Terminal {content:?} did not match near: {:?}, skipping variant",
+                            data.chars().take(5).collect::<String>()
+                        );
                     }
-                    Atom::NonTerminal { name } => {
-                        let subrule = &self.tree[name];
-                        let Ok(processed) =
-                            self.match_against(subrule, to_watch, data, offset + proc, &mut sub)
-                        else {
-                            continue 'varloop;
-                        };
-                        data = &data[processed..];
-                        proc += processed;
+
+                    memo.furthest.expect(offset, || Furthest::atom(atom));
+                    return Err(());
+                }
+
+                if self.debug {
+                    eprintln!("Terminal {content:?} matched completely");
+                }
+
+                Ok(content.len())
+            }
+            Atom::NonTerminal { name } => {
+                self.match_against(&self.tree[name], data, offset, outp, memo)
+            }
+            Atom::Optional { atom } => {
+                Ok(self.match_atom(atom, data, offset, outp, memo).unwrap_or(0))
+            }
+            Atom::Group { variants } => self
+                .match_variants(variants, data, offset, outp, memo)
+                .map(|(_, proc)| proc),
+            Atom::CharClass { class } => match data.chars().next() {
+                Some(c) if class.contains(c) => Ok(c.len_utf8()),
+                _ => {
+                    if self.debug {
+                        eprintln!(
+                            "Class {class:?} did not match near: {:?}, skipping variant",
+                            data.chars().take(5).collect::<String>()
+                        );
                     }
+
+                    memo.furthest.expect(offset, || Furthest::atom(atom));
+                    Err(())
                 }
+            },
+            Atom::ZeroOrMore { atom } => Ok(self.match_repeat(atom, data, offset, outp, memo).0),
+            Atom::OneOrMore { atom } => match self.match_repeat(atom, data, offset, outp, memo) {
+                (_, 0) => Err(()),
+                (proc, _) => Ok(proc),
+            },
+        }
+    }
+
+    /// Match the atom as many times as possible (greedy), returns the processed length and how
+    /// many times it matched
+    fn match_repeat<'e>(
+        &'e self,
+        atom: &Atom,
+        data: &str,
+        offset: usize,
+        outp: &mut Vec<Rc<Node>>,
+        memo: &mut Memo<'e>,
+    ) -> (usize, usize) {
+        let mut proc = 0;
+        let mut times = 0;
+
+        let mut len = outp.len();
+        while let Ok(processed) = self.match_atom(atom, &data[proc..], offset + proc, outp, memo) {
+            proc += processed;
+            times += 1;
+
+            // Matched the empty string, it would loop forever, and the nodes of this last
+            // iteration add nothing to the tree
+            if processed == 0 {
+                outp.truncate(len);
+                break;
             }
+            len = outp.len();
+        }
 
-            if self.debug {
-                eprintln!("Done matching against {rule:?}");
+        (proc, times)
+    }
+
+    /// Match the data against the initial rule, and get the whole tree of rules that matched
+    pub fn match_tree(
+        &self,
+        initial: &str,
+        data: &str,
+        opts: &MatchOptions,
+    ) -> Result<Node, MatchError> {
+        let Some(initial) = self.tree.get(initial) else {
+            return Err(MatchError::BadInitialRule(initial.into()));
+        };
+
+        let res = match opts.algorithm {
+            Algorithm::Descent => {
+                let mut outp = Vec::new();
+                let mut memo = Memo {
+                    packrat: opts.packrat,
+                    ..Default::default()
+                };
+                match self.match_against(initial, data, 0, &mut outp, &mut memo) {
+                    Ok(proc) if !opts.full || proc == data.len() => {
+                        Ok(Rc::unwrap_or_clone(outp.remove(0)))
+                    }
+                    res => Err(Failure {
+                        longest: res.ok(),
+                        furthest: memo.furthest,
+                    }),
+                }
             }
+            Algorithm::Backtrack => backtrack::Backtracker::new(self, data).run(initial, opts.full),
+            Algorithm::Earley => earley::Chart::new(self, data).run(&initial.name, opts.full),
+        };
+
+        let Failure { longest, furthest } = match res {
+            Ok(node) => return Ok(node),
+            Err(failure) => failure,
+        };
 
-            // All atoms matched, save and return the slice
-            // If this rule matched, add to the output vector
-            if to_watch.contains(&rule.name) {
-                outp.push((offset, offset + proc));
+        match longest {
+            // Nothing failed after the prefix that matched, so the rest of the input is left over,
+            // what failed right there is expected too
+            Some(end) if end >= furthest.offset => {
+                let near = data[end..].chars().take(10).collect();
+                let mut expected = if end == furthest.offset {
+                    furthest.expected
+                } else {
+                    BTreeSet::new()
+                };
+                expected.insert("end of input".into());
+                let expected = expected.into_iter().collect::<Vec<_>>().join(", ");
+                Err(MatchError::UnconsumedInput(end, near, expected))
             }
-            outp.extend(sub);
-            return Ok(proc);
+            _ => Err(furthest.into_error(data)),
         }
-
-        Err(())
     }
 
     /// Get the matches of a rule in the given data, starting from the given rule
@@ -160,57 +1130,328 @@ impl Engine {
         initial: &str,
         to_watch: &[String],
         data: &str,
-    ) -> Result<Vec<(usize, usize)>, MatchError> {
-        if !self.tree.contains_key(initial) {
-            return Err(MatchError::BadInitialRule(initial.into()));
-        }
-
+        opts: &MatchOptions,
+    ) -> Result<Vec<Match>, MatchError> {
         for rule in to_watch {
             if !self.tree.contains_key(rule) {
                 return Err(MatchError::BadWatchRule(rule.clone()));
             }
         }
 
+        let tree = self.match_tree(initial, data, opts)?;
         let mut outp = Vec::new();
-        let _ = self
-            .match_against(&self.tree[initial], to_watch, data, 0, &mut outp)
-            .map_err(|_| MatchError::NoMatches)?;
+        tree.watched(to_watch, &mut outp);
         Ok(outp)
     }
 
-    /// Check if the given rule causes a recursion error
-    /// TODO: Currently this checks for cicles in O(n) time each rule, in total O( n^2 )
-    /// This could be improved to check if exists a cycle in one pass in O(n)
-    fn check_recursion(idx: usize, rule: &Rule, in_stack: &mut [bool], rules: &[Rule]) -> bool {
-        // Mark this as visited
-        in_stack[idx] = true;
+    /// Find the occurrences of the rule anywhere in the data (like `grep`). With
+    /// [`Algorithm::Backtrack`] and [`Algorithm::Earley`] the longest match at each offset is
+    /// used, with [`Algorithm::Descent`] it is the match that the descent commits to, which may
+    /// be shorter.
+    /// If not `overlapping`, the search continues after the end of each occurrence, otherwise
+    /// at the next character. Empty matches are ignored
+    /// [`MatchOptions::full`] is ignored, the occurrences never need to reach the end of the data
+    pub fn search(
+        &self,
+        rule: &str,
+        data: &str,
+        opts: &MatchOptions,
+        overlapping: bool,
+    ) -> Result<Vec<Occurrence>, MatchError> {
+        let Some(rule) = self.tree.get(rule) else {
+            return Err(MatchError::BadInitialRule(rule.into()));
+        };
 
-        for variant in rule.variants.iter() {
-            if variant.items.len() == 0 {
-                continue;
+        let opts = MatchOptions {
+            full: false,
+            ..opts.clone()
+        };
+
+        // Earley can try every offset at once, the other ones try them one by one
+        let ends = match opts.algorithm {
+            Algorithm::Earley => Some(earley::Chart::new(self, data).search(&rule.name)),
+            Algorithm::Descent | Algorithm::Backtrack => None,
+        };
+        let longest_at = |offset: usize| match (&ends, opts.algorithm) {
+            (Some(ends), _) => ends[offset],
+            (None, Algorithm::Backtrack) => backtrack::Backtracker::new(self, &data[offset..])
+                .longest(rule)
+                .map(|end| offset + end),
+            // Without building the error of every offset that fails, it is not needed
+            (None, _) => {
+                let mut memo = Memo {
+                    packrat: opts.packrat,
+                    ..Default::default()
+                };
+                let found =
+                    self.match_against(rule, &data[offset..], 0, &mut Vec::new(), &mut memo);
+                found.ok().map(|proc| offset + proc)
             }
+        };
 
-            if let crate::ast::Atom::NonTerminal { name } = &variant.items[0] {
-                for (cidx, rule) in rules.iter().enumerate() {
-                    if rule.name != *name {
-                        continue;
-                    }
+        let mut outp = Vec::new();
+        let mut line = 1;
+        let mut counted = 0;
+        let mut offset = 0;
+        while let Some(c) = data[offset..].chars().next() {
+            let next = offset + c.len_utf8();
+            let end = match longest_at(offset) {
+                Some(end) if end > offset => end,
+                _ => {
+                    offset = next;
+                    continue;
+                }
+            };
+
+            line += data[counted..offset].matches('\n').count();
+            counted = offset;
+            outp.push(Occurrence {
+                line,
+                start: offset,
+                end,
+            });
 
-                    // If is in stack then return
-                    if in_stack[cidx] {
-                        return true;
+            offset = if overlapping { next } else { end };
+        }
+
+        Ok(outp)
+    }
+
+    /// The rules that can be reached from the given one (itself included), sorted by name
+    fn reachable(&self, start: &str) -> Vec<&Rule> {
+        let mut found = BTreeSet::from([start]);
+        let mut stack = vec![start];
+        while let Some(name) = stack.pop() {
+            for atom in self.tree[name]
+                .variants
+                .iter()
+                .flat_map(|variant| variant.items.iter())
+            {
+                atom.for_each_non_terminal(&mut |name| {
+                    if found.insert(name) {
+                        stack.push(name);
                     }
+                });
+            }
+        }
 
-                    if Self::check_recursion(cidx, rule, in_stack, rules) {
-                        return true;
+        found.into_iter().map(|name| &self.tree[name]).collect()
+    }
+
+    /// The rules that use each rule (their indexes, each one once), the ones that have to be
+    /// checked again when something is found about it
+    fn dependents(rules: &[Rule]) -> BTreeMap<&str, Vec<usize>> {
+        let mut dependents: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+        for (idx, rule) in rules.iter().enumerate() {
+            for atom in rule
+                .variants
+                .iter()
+                .flat_map(|variant| variant.items.iter())
+            {
+                atom.for_each_non_terminal(&mut |name| {
+                    let users = dependents.entry(name).or_default();
+                    if users.last() != Some(&idx) {
+                        users.push(idx);
                     }
+                });
+            }
+        }
+        dependents
+    }
+
+    /// The rules that may match the empty string (a rule is nullable if all the atoms of one of
+    /// its variants are, see [`Engine::nullable_atom`])
+    /// Every rule is checked once, and again only when one of the rules it uses becomes nullable
+    fn nullable_rules(rules: &[Rule]) -> BTreeSet<String> {
+        let dependents = Self::dependents(rules);
+        let mut nullable = BTreeSet::new();
+
+        let mut pending: Vec<usize> = (0..rules.len()).collect();
+        while let Some(idx) = pending.pop() {
+            let rule = &rules[idx];
+            if nullable.contains(&rule.name) {
+                continue;
+            }
+
+            let empty = rule.variants.iter().any(|variant| {
+                let mut items = variant.items.iter();
+                items.all(|atom| Self::nullable_atom(atom, &nullable))
+            });
+            if empty {
+                nullable.insert(rule.name.clone());
+                pending.extend(dependents.get(rule.name.as_str()).into_iter().flatten());
+            }
+        }
+
+        nullable
+    }
+
+    /// The minimum height of the derivation trees of each rule, see [`Engine::min_height`]
+    fn min_heights(rules: &[Rule]) -> BTreeMap<String, usize> {
+        Self::settle(rules, Self::rule_height)
+    }
+
+    /// The minimum length of the strings of each rule, see [`Engine::min_len`]
+    fn min_lengths(rules: &[Rule]) -> BTreeMap<String, usize> {
+        Self::settle(rules, Self::rule_length)
+    }
+
+    /// The lowest value of each rule, given how to find it from the values known so far (it
+    /// is never lower than the values of the rules it uses), the rules without one are not here
+    /// Like Dijkstra, the rules are settled from the lowest to the highest, and a rule is only
+    /// checked again when one of the rules it uses is settled
+    fn settle(
+        rules: &[Rule],
+        value: impl Fn(&Rule, &BTreeMap<String, usize>) -> Option<usize>,
+    ) -> BTreeMap<String, usize> {
+        let dependents = Self::dependents(rules);
+        let mut values = BTreeMap::new();
+
+        let mut queue = BinaryHeap::new();
+        for (idx, rule) in rules.iter().enumerate() {
+            if let Some(found) = value(rule, &values) {
+                queue.push(Reverse((found, idx)));
+            }
+        }
+
+        while let Some(Reverse((found, idx))) = queue.pop() {
+            let name = &rules[idx].name;
+            if values.contains_key(name) {
+                continue;
+            }
+            values.insert(name.clone(), found);
+
+            for user in dependents.get(name.as_str()).into_iter().flatten() {
+                let rule = &rules[*user];
+                if values.contains_key(&rule.name) {
+                    continue;
+                }
+                if let Some(found) = value(rule, &values) {
+                    queue.push(Reverse((found, *user)));
                 }
             }
         }
 
-        in_stack[idx] = false;
+        values
+    }
+
+    /// The minimum height of the trees of a rule (one more than its lowest variant), given the
+    /// heights known so far, None if it cannot generate any string (yet)
+    fn rule_height(rule: &Rule, heights: &BTreeMap<String, usize>) -> Option<usize> {
+        let variants = rule.variants.iter();
+        let best = variants
+            .filter_map(|variant| Self::variant_height(variant, heights))
+            .min()?;
+        Some(best + 1)
+    }
+
+    /// The minimum height of the trees of a variant (the highest of its atoms), given the
+    /// heights known so far, None if it cannot generate any string (yet)
+    fn variant_height(variant: &RuleVariant, heights: &BTreeMap<String, usize>) -> Option<usize> {
+        variant.items.iter().try_fold(0, |max, atom| {
+            Some(max.max(Self::atom_height(atom, heights)?))
+        })
+    }
+
+    /// Same as [`Engine::variant_height`], for a single atom
+    fn atom_height(atom: &Atom, heights: &BTreeMap<String, usize>) -> Option<usize> {
+        match atom {
+            Atom::Terminal { .. } | Atom::CharClass { .. } => Some(0),
+            Atom::Optional { .. } | Atom::ZeroOrMore { .. } => Some(0),
+            Atom::NonTerminal { name } => heights.get(name).copied(),
+            Atom::OneOrMore { atom } => Self::atom_height(atom, heights),
+            Atom::Group { variants } => variants
+                .iter()
+                .filter_map(|variant| Self::variant_height(variant, heights))
+                .min(),
+        }
+    }
+
+    /// The minimum length of the strings of a rule (the one of its shortest variant), given
+    /// the lengths known so far, None if it cannot generate any string (yet)
+    fn rule_length(rule: &Rule, lens: &BTreeMap<String, usize>) -> Option<usize> {
+        let variants = rule.variants.iter();
+        variants
+            .filter_map(|variant| Self::variant_length(variant, lens))
+            .min()
+    }
+
+    /// The minimum length of the strings of a variant (the sum of its atoms), given the
+    /// lengths known so far, None if it cannot generate any string (yet)
+    fn variant_length(variant: &RuleVariant, lens: &BTreeMap<String, usize>) -> Option<usize> {
+        variant
+            .items
+            .iter()
+            .try_fold(0, |sum, atom| Some(sum + Self::atom_length(atom, lens)?))
+    }
+
+    /// Same as [`Engine::variant_length`], for a single atom
+    fn atom_length(atom: &Atom, lens: &BTreeMap<String, usize>) -> Option<usize> {
+        match atom {
+            Atom::Terminal { content } => Some(content.chars().count()),
+            Atom::CharClass { .. } => Some(1),
+            Atom::Optional { .. } | Atom::ZeroOrMore { .. } => Some(0),
+            Atom::NonTerminal { name } => lens.get(name).copied(),
+            Atom::OneOrMore { atom } => Self::atom_length(atom, lens),
+            Atom::Group { variants } => variants
+                .iter()
+                .filter_map(|variant| Self::variant_length(variant, lens))
+                .min(),
+        }
+    }
+
+    /// The non-terminals a sequence of atoms may start with, looking inside repetitions and
+    /// groups, and past the atoms that may match the empty string (given the `nullable` rules)
+    /// Each one comes with the nullable rules skipped before it, `skipped` has the ones skipped
+    /// before the sequence
+    fn leading_non_terminals<'a>(
+        items: &'a [Atom],
+        nullable: &BTreeSet<String>,
+        skipped: &mut Vec<&'a str>,
+        outp: &mut Vec<(&'a str, Vec<&'a str>)>,
+    ) {
+        let len = skipped.len();
+        for atom in items {
+            match atom {
+                Atom::Terminal { .. } | Atom::CharClass { .. } => {}
+                Atom::NonTerminal { name } => outp.push((name, skipped.clone())),
+                Atom::Optional { atom } | Atom::ZeroOrMore { atom } | Atom::OneOrMore { atom } => {
+                    let atom = std::slice::from_ref(atom.as_ref());
+                    Self::leading_non_terminals(atom, nullable, skipped, outp);
+                }
+                Atom::Group { variants } => {
+                    for variant in variants {
+                        Self::leading_non_terminals(&variant.items, nullable, skipped, outp);
+                    }
+                }
+            }
+
+            if !Self::nullable_atom(atom, nullable) {
+                break;
+            }
+            atom.for_each_non_terminal(&mut |name| {
+                if nullable.contains(name) {
+                    skipped.push(name);
+                }
+            });
+        }
+        skipped.truncate(len);
+    }
 
-        return false;
+    /// Whether the atom may match the empty string, like `""`, `"a"?`, `("a" | ε)` or one of
+    /// the `nullable` rules
+    fn nullable_atom(atom: &Atom, nullable: &BTreeSet<String>) -> bool {
+        match atom {
+            Atom::Terminal { content } => content.is_empty(),
+            Atom::CharClass { .. } => false,
+            Atom::NonTerminal { name } => nullable.contains(name),
+            Atom::Optional { .. } | Atom::ZeroOrMore { .. } => true,
+            Atom::OneOrMore { atom } => Self::nullable_atom(atom, nullable),
+            Atom::Group { variants } => variants.iter().any(|variant| {
+                let mut items = variant.items.iter();
+                items.all(|atom| Self::nullable_atom(atom, nullable))
+            }),
+        }
     }
 
     /// Create a new instance of this engine and verify if there is any possible error at
@@ -237,27 +1478,46 @@ impl Engine {
         // Check if all the non-terminals are valid
         for rule in ast {
             for variant in rule.variants.iter() {
+                let mut missing = None;
                 for item in variant.items.iter() {
-                    let Atom::NonTerminal { name } = item else {
-                        continue;
-                    };
-                    if names.contains(name) {
-                        continue;
-                    };
+                    item.for_each_non_terminal(&mut |name| {
+                        if missing.is_none() && !names.contains(name) {
+                            missing = Some(name);
+                        }
+                    });
+                }
+                if let Some(name) = missing {
                     return Err(BuildError::InexistentNonTerminals(
                         rule.name.clone(),
-                        name.clone(),
+                        name.to_string(),
                     ));
                 }
             }
         }
 
-        // Check if a rule causes infinite recursion
-        let mut in_stack = vec![false; ast.len()];
-        for (idx, rule) in ast.iter().enumerate() {
-            if Self::check_recursion(idx, rule, &mut in_stack, ast) {
-                return Err(BuildError::InfinityRecursion(rule.name.clone()));
+        // Find the left-recursive rules (the cycles of the left corners), those are fine as
+        // long as they can stop recursing at some point (if none of the variants can be matched
+        // without recursing, the rule never ends), all the cycles that never end are reported
+        let min_height = Self::min_heights(ast);
+        let min_len = Self::min_lengths(ast);
+        let nullable = Self::nullable_rules(ast);
+        let corners = cycles::LeftCorners::new(ast, &nullable);
+        let mut left_recursive = BTreeSet::new();
+        let mut infinite = Vec::new();
+        for component in corners.cycles() {
+            let endless = component
+                .iter()
+                .find(|idx| !min_height.contains_key(&ast[**idx].name));
+            if let Some(start) = endless {
+                let cycle = corners.cycle(*start, &component);
+                infinite.push(cycle.into_iter().map(String::from).collect());
             }
+
+            left_recursive.extend(component.iter().map(|idx| ast[*idx].name.clone()));
+        }
+
+        if !infinite.is_empty() {
+            return Err(BuildError::InfinityRecursion(infinite));
         }
 
         let mut all = BTreeMap::new();
@@ -266,6 +1526,286 @@ impl Engine {
             all.insert(rule.name.clone(), rule.clone());
         }
 
-        Ok(Self { tree: all, debug })
+        Ok(Self {
+            tree: all,
+            left_recursive,
+            min_height,
+            min_len,
+            debug,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn engine(grammar: &str) -> Engine {
+        Engine::build(&ast::parse(grammar).unwrap(), false).unwrap()
+    }
+
+    #[test]
+    fn unproductive_variants_are_not_generated() {
+        let engine =
+            engine("<s> ::= \"a\" | <bad>\n<o> ::= \"a\" <bad>? <bad>*\n<bad> ::= \"x\" <bad>\n");
+        let opts = GenOptions::default();
+
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            for rule in ["s", "o"] {
+                let samples = engine.samples(rule, &opts, &mut rng).unwrap();
+                for sample in samples.take(10) {
+                    assert_eq!(sample.unwrap(), "a");
+                }
+                assert_eq!(engine.gen_random(rule, &mut rng).unwrap(), "a");
+            }
+        }
+    }
+
+    #[test]
+    fn generation_is_bounded_and_reaches_the_minimum_length() {
+        let engine = engine("<s> ::= <s> <s> <s> | \"b\"\n<p> ::= \"(\" <p> \")\" | \"x\"\n");
+        let bounded = GenOptions {
+            max_depth: Some(64),
+            max_nodes: Some(1000),
+            ..Default::default()
+        };
+        let long = GenOptions {
+            min_len: Some(30),
+            ..bounded.clone()
+        };
+
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut samples = engine.samples("s", &bounded, &mut rng).unwrap();
+            assert!(samples.next().unwrap().unwrap().len() < 2000);
+
+            let mut samples = engine.samples("p", &long, &mut rng).unwrap();
+            assert!(samples.next().unwrap().unwrap().len() >= 30);
+        }
+    }
+
+    #[test]
+    fn default_generation_always_ends() {
+        let engine = engine("<s> ::= <s> <s> <s> | \"b\"\n");
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            assert!(engine.gen_random("s", &mut rng).is_ok());
+        }
+    }
+
+    #[test]
+    fn generation_reaches_a_length_window() {
+        // Both need to know what the open rules still have to add to stop in time
+        let engine = engine("<p> ::= \"(\" <p> \")\" | \"x\"\n<l> ::= <l> \"a\" | \"b\"\n");
+        let mut rng = StdRng::seed_from_u64(0);
+        for (rule, min, max) in [("p", 30, 40), ("l", 50, 60)] {
+            let opts = GenOptions {
+                min_len: Some(min),
+                max_len: Some(max),
+                ..Default::default()
+            };
+            for sample in engine.samples(rule, &opts, &mut rng).unwrap().take(20) {
+                let len = sample.unwrap().len();
+                assert!((min..=max).contains(&len), "{rule}: {len}");
+            }
+        }
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_samples() {
+        let engine = engine(include_str!("../example.bnf"));
+        let opts = GenOptions::default();
+        let run = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let samples = engine.samples("date", &opts, &mut rng).unwrap();
+            samples.take(50).map(Result::unwrap).collect::<Vec<_>>()
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn zero_weights_are_never_chosen() {
+        // Unless all the variants have it, like in the group
+        let engine = engine("<s> ::= \"a\" @0 | \"b\" @2 | (\"c\" @0 | \"d\" @0) \"e\"\n");
+        let opts = GenOptions::default();
+        let mut rng = StdRng::seed_from_u64(0);
+        let samples = engine.samples("s", &opts, &mut rng).unwrap();
+        let found: BTreeSet<_> = samples.take(200).map(Result::unwrap).collect();
+        let expected = ["b", "ce", "de"].map(String::from);
+        assert_eq!(found, BTreeSet::from(expected));
+    }
+
+    #[test]
+    fn enumeration_ignores_the_unreachable_rules() {
+        let engine = engine("<s> ::= \"a\" | \"b\" <s>\n<str> ::= \"\\\"\" [^\"]* \"\\\"\"\n");
+        let opts = EnumOptions {
+            max_len: Some(3),
+            max_count: None,
+        };
+        assert_eq!(engine.enumerate("s", &opts).unwrap(), ["a", "ba", "bba"]);
+    }
+
+    #[test]
+    fn counting_ignores_the_unreachable_rules() {
+        let engine = engine("<s> ::= \"a\" | \"b\"\n<junk> ::= <junk> | \"z\"\n");
+        let counts = engine.count_derivations("s", 2).unwrap();
+        assert_eq!(counts, [0u32.into(), 2u32.into(), 0u32.into()]);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut samples = engine.uniform_samples("s", 1, &mut rng).unwrap();
+        assert!(["a", "b"].contains(&samples.next().unwrap().unwrap().as_str()));
+    }
+
+    #[test]
+    fn packrat_builds_the_same_tree() {
+        let engine = engine("<list> ::= <item> \",\" <list> | <item>\n<item> ::= [a-z]+\n");
+        let data = ["ab"; 50].join(",");
+
+        let mut opts = MatchOptions::default();
+        let plain = engine.match_tree("list", &data, &opts).unwrap();
+        opts.packrat = true;
+        let packrat = engine.match_tree("list", &data, &opts).unwrap();
+        assert_eq!(format!("{plain:?}"), format!("{packrat:?}"));
+    }
+
+    #[test]
+    fn earley_builds_long_repetitions() {
+        let engine = engine("<d> ::= <digit>+\n<digit> ::= [0-9]\n");
+        let data = "7".repeat(10000);
+        let opts = MatchOptions {
+            full: true,
+            algorithm: Algorithm::Earley,
+            ..Default::default()
+        };
+        let tree = engine.match_tree("d", &data, &opts).unwrap();
+        assert_eq!(tree.children.len(), data.len());
+        assert_eq!((tree.children[1].start, tree.children[1].end), (1, 2));
+    }
+
+    #[test]
+    fn a_partial_match_leaves_input_unconsumed() {
+        let engine = engine(include_str!("../example.bnf"));
+        for algorithm in [Algorithm::Descent, Algorithm::Backtrack, Algorithm::Earley] {
+            let opts = MatchOptions {
+                full: true,
+                algorithm,
+                ..Default::default()
+            };
+            let err = engine.match_tree("date", "10/5/2080garbage", &opts);
+            assert!(
+                matches!(err, Err(MatchError::UnconsumedInput(9, _, _))),
+                "{algorithm:?}: {err:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn backtracking_accepts_what_the_descent_commits_away() {
+        // The descent commits to the first variant of <x>, so nothing is left for "b"
+        let engine = engine("<a> ::= <x> \"b\"\n<x> ::= \"b\" | \"\"\n");
+        let mut opts = MatchOptions {
+            full: true,
+            algorithm: Algorithm::Backtrack,
+            ..Default::default()
+        };
+        let tree = engine.match_tree("a", "b", &opts).unwrap();
+        assert_eq!((tree.children[0].variant, tree.end), (1, 1));
+
+        opts.algorithm = Algorithm::Descent;
+        assert!(engine.match_tree("a", "b", &opts).is_err());
+    }
+
+    #[test]
+    fn search_uses_the_longest_match() {
+        let engine = engine("<s> ::= \"a\" | \"a\" \"b\"\n");
+        for (algorithm, len) in [
+            (Algorithm::Descent, 1),
+            (Algorithm::Backtrack, 2),
+            (Algorithm::Earley, 2),
+        ] {
+            let opts = MatchOptions {
+                algorithm,
+                ..Default::default()
+            };
+            let found = engine.search("s", "xxabxab", &opts, false).unwrap();
+            let found: Vec<_> = found.iter().map(|occ| (occ.start, occ.end)).collect();
+            assert_eq!(found, [(2, 2 + len), (5, 5 + len)], "{algorithm:?}");
+        }
+    }
+
+    /// The rules and ranges of the tree, the children between parentheses
+    fn shape(node: &Node) -> String {
+        let children: Vec<_> = node.children.iter().map(|child| shape(child)).collect();
+        let mut outp = format!("{}[{}..{}]", node.rule, node.start, node.end);
+        if !children.is_empty() {
+            outp += &format!("({})", children.join(" "));
+        }
+        outp
+    }
+
+    #[test]
+    fn descent_grows_left_recursion() {
+        let direct = engine("<expr> ::= <expr> \"-\" <term> | <term>\n<term> ::= [0-9]\n");
+        let indirect = engine("<a> ::= <b> \"x\" | \"y\"\n<b> ::= <a> \"z\" | \"w\"\n");
+        for packrat in [false, true] {
+            let opts = MatchOptions {
+                full: true,
+                packrat,
+                ..Default::default()
+            };
+
+            // Left-associative: (1-2)-3
+            let tree = direct.match_tree("expr", "1-2-3", &opts).unwrap();
+            assert_eq!(
+                shape(&tree),
+                "expr[0..5](expr[0..3](expr[0..1](term[0..1]) term[2..3]) term[4..5])",
+                "packrat: {packrat}"
+            );
+
+            let tree = indirect.match_tree("a", "yzxzx", &opts).unwrap();
+            assert_eq!(
+                shape(&tree),
+                "a[0..5](b[0..4](a[0..3](b[0..2](a[0..1]))))",
+                "packrat: {packrat}"
+            );
+            let tree = indirect.match_tree("a", "wxzx", &opts).unwrap();
+            assert_eq!(
+                shape(&tree),
+                "a[0..4](b[0..3](a[0..2](b[0..1])))",
+                "packrat: {packrat}"
+            );
+            assert!(indirect.match_tree("a", "yzxz", &opts).is_err());
+        }
+    }
+
+    #[test]
+    fn hidden_left_recursion_shows_the_cycle() {
+        let tree = ast::parse("<maybe_empty> ::= \"m\" | ε\n<a> ::= <maybe_empty> <a> \"x\"\n");
+        let err = Engine::build(&tree.unwrap(), false).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Some rules may cause an infinite recursion: a -> maybe_empty -> a"
+        );
+    }
+
+    #[test]
+    fn heights_do_not_depend_on_the_rule_order() {
+        // Each rule uses the next one, the one that ends the chain is the last one
+        let len = 500;
+        let mut grammar = String::new();
+        for idx in 0..len {
+            grammar += &format!("<r{idx}> ::= \"x\" <r{0}> | <r{0}>\n", idx + 1);
+        }
+        grammar += &format!("<r{len}> ::= \"x\" | ε\n");
+        let engine = engine(&grammar);
+
+        assert_eq!(engine.min_height["r0"], len + 1);
+        assert_eq!(engine.min_height[&format!("r{len}")], 1);
+        let rules: Vec<_> = engine.tree.values().cloned().collect();
+        assert_eq!(Engine::nullable_rules(&rules).len(), len + 1);
     }
 }
@@ -0,0 +1,75 @@
+//! The atoms of some rules, numbered so the results of each one can be kept by its index, see
+//! [`Atoms`]
+
+use crate::ast::{Atom, CharClass, Rule, RuleVariant};
+use std::collections::BTreeMap;
+
+/// An atom, with the indexes of the atoms inside of it instead of the atoms themselves
+#[derive(Debug)]
+pub(super) enum Part<'e> {
+    Terminal(&'e str),
+    NonTerminal(&'e str),
+    Class(&'e CharClass),
+    Optional(usize),
+    ZeroOrMore(usize),
+    OneOrMore(usize),
+    /// The indexes of the atoms of each variant
+    Group(Vec<Vec<usize>>),
+}
+
+/// The atoms of some rules (and the ones inside of them), each one with its own index in
+/// [`Atoms::parts`], the inner atoms before the outer ones
+/// The algorithms that keep something for every atom walk the rules through the indexes, and
+/// keep it by index too
+#[derive(Debug, Default)]
+pub(super) struct Atoms<'e> {
+    /// The indexes of the atoms of each variant of each rule
+    pub(super) rules: BTreeMap<&'e str, Vec<Vec<usize>>>,
+    pub(super) parts: Vec<Part<'e>>,
+}
+
+impl<'e> Atoms<'e> {
+    pub(super) fn new(rules: &[&'e Rule]) -> Self {
+        let mut atoms = Self::default();
+        for rule in rules {
+            let variants = atoms.add_variants(&rule.variants);
+            atoms.rules.insert(&rule.name, variants);
+        }
+        atoms
+    }
+
+    fn add_variants(&mut self, variants: &'e [RuleVariant]) -> Vec<Vec<usize>> {
+        variants
+            .iter()
+            .map(|variant| variant.items.iter().map(|atom| self.add(atom)).collect())
+            .collect()
+    }
+
+    /// Add the atom (and the ones inside of it), returns its index
+    fn add(&mut self, atom: &'e Atom) -> usize {
+        let part = match atom {
+            Atom::Terminal { content } => Part::Terminal(content),
+            Atom::NonTerminal { name } => Part::NonTerminal(name),
+            Atom::CharClass { class } => Part::Class(class),
+            Atom::Optional { atom } => Part::Optional(self.add(atom)),
+            Atom::ZeroOrMore { atom } => Part::ZeroOrMore(self.add(atom)),
+            Atom::OneOrMore { atom } => Part::OneOrMore(self.add(atom)),
+            Atom::Group { variants } => Part::Group(self.add_variants(variants)),
+        };
+
+        self.parts.push(part);
+        self.parts.len() - 1
+    }
+
+    /// Every sequence of atoms: the variants of the rules and of the groups
+    pub(super) fn sequences(&self) -> impl Iterator<Item = &[usize]> {
+        let groups = self.parts.iter().filter_map(|part| match part {
+            Part::Group(variants) => Some(variants),
+            _ => None,
+        });
+        self.rules
+            .values()
+            .chain(groups)
+            .flat_map(|variants| variants.iter().map(Vec::as_slice))
+    }
+}
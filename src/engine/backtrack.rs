@@ -0,0 +1,237 @@
+//! A matcher with full backtracking, see [`Backtracker`]
+
+use super::{Engine, Failure, Furthest, MatchResult, Node};
+use crate::ast::{Atom, Rule, RuleVariant};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// What happened in the current derivation, see [`Backtracker::trail`]
+#[derive(Debug, Clone, Copy)]
+enum Event<'e> {
+    /// The given variant of the rule started matching at the offset
+    Start(&'e Rule, usize, usize),
+    /// The last started rule ended at the offset
+    End(usize),
+}
+
+/// The continuation of a match, receives the offset where the previous atoms ended and returns
+/// whether the rest of the input was matched too
+type Cont<'k, 'e, 'd> = dyn FnMut(&mut Backtracker<'e, 'd>, usize) -> bool + 'k;
+
+/// Unlike [`Engine::match_against`], which commits to the first variant that succeeds, this
+/// matcher tries every variant (and every amount of repetitions) until the *whole* parent
+/// matches, so it accepts exactly the strings that the grammar can generate.
+///
+/// It is written in continuation passing style: every function receives what must be matched
+/// after it (`k`) and only returns true if both itself and the continuation matched. When the
+/// continuation fails, the next alternative is tried.
+///
+/// The left-recursive rules are curtailed: a rule cannot be nested at the same offset more
+/// times than the characters left in the input (plus one), since each nesting level must match
+/// a longer string than the one inside it (except for cycles that match the empty string,
+/// which are useless anyway)
+///
+/// The worst case is exponential, and the stack grows with the length of the input
+pub(super) struct Backtracker<'e, 'd> {
+    engine: &'e Engine,
+    data: &'d str,
+    /// The rules of the current derivation, restored when backtracking
+    trail: Vec<Event<'e>>,
+    /// The furthest offset where the initial rule ended
+    longest: Option<usize>,
+    /// Where the input stopped matching
+    furthest: Furthest,
+    /// How many times each left-recursive rule is nested at each offset
+    nested: HashMap<(&'e str, usize), usize>,
+}
+
+impl<'e, 'd> Backtracker<'e, 'd> {
+    pub(super) fn new(engine: &'e Engine, data: &'d str) -> Self {
+        Self {
+            engine,
+            data,
+            trail: Vec::new(),
+            longest: None,
+            furthest: Furthest::default(),
+            nested: HashMap::new(),
+        }
+    }
+
+    /// Match the initial rule, if `full` the whole input must be matched
+    pub(super) fn run(mut self, initial: &'e Rule, full: bool) -> MatchResult {
+        let len = self.data.len();
+        let found = self.rule(initial, 0, &mut |this, proc| {
+            this.longest = this.longest.max(Some(proc));
+            !full || proc == len
+        });
+
+        if !found {
+            return Err(Failure {
+                longest: self.longest,
+                furthest: self.furthest,
+            });
+        }
+
+        // Build the tree from the events
+        let mut stack = Vec::new();
+        let mut root = None;
+        for event in self.trail {
+            match event {
+                Event::Start(rule, variant, start) => stack.push(Node {
+                    rule: rule.name.clone(),
+                    variant,
+                    start,
+                    end: start,
+                    children: Vec::new(),
+                }),
+                Event::End(end) => {
+                    let mut node = stack.pop().expect("Every end has its start");
+                    node.end = end;
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(Rc::new(node)),
+                        None => root = Some(node),
+                    }
+                }
+            }
+        }
+
+        Ok(root.expect("The initial rule matched"))
+    }
+
+    /// Where the longest match of the rule at the start of the input ends, every derivation
+    /// is tried (unless one reaches the end of the input)
+    pub(super) fn longest(mut self, rule: &'e Rule) -> Option<usize> {
+        let len = self.data.len();
+        self.rule(rule, 0, &mut |this, proc| {
+            this.longest = this.longest.max(Some(proc));
+            proc == len
+        });
+
+        self.longest
+    }
+
+    /// Match a rule (any of its variants) and then the continuation
+    fn rule(&mut self, rule: &'e Rule, offset: usize, k: &mut Cont<'_, 'e, 'd>) -> bool {
+        if self.engine.debug {
+            eprintln!(
+                "[*] Backtracking against: {}, at offset: {offset}",
+                rule.name
+            );
+        }
+
+        if self.engine.left_recursive.contains(&rule.name) {
+            return self.left_recursive(rule, offset, k);
+        }
+
+        self.variants_of(rule, offset, k)
+    }
+
+    /// Match a left-recursive rule (curtailed) and then the continuation
+    fn left_recursive(&mut self, rule: &'e Rule, offset: usize, k: &mut Cont<'_, 'e, 'd>) -> bool {
+        let key = (rule.name.as_str(), offset);
+        let limit = self.data.len() - offset + 1;
+        if self.nested.get(&key).copied().unwrap_or(0) >= limit {
+            return false;
+        }
+
+        // Only count it while the rule itself is being matched, not while the continuation is
+        *self.nested.entry(key).or_default() += 1;
+        let found = self.variants_of(rule, offset, &mut |this, end| {
+            *this.nested.get_mut(&key).unwrap() -= 1;
+            let found = k(this, end);
+            *this.nested.get_mut(&key).unwrap() += 1;
+            found
+        });
+        *self.nested.get_mut(&key).unwrap() -= 1;
+
+        found
+    }
+
+    /// Match the variants of a rule (saving them to the trail) and then the continuation
+    /// If the rule never matches (no matter the continuation), it is expected at the offset
+    fn variants_of(&mut self, rule: &'e Rule, offset: usize, k: &mut Cont<'_, 'e, 'd>) -> bool {
+        let mut matched = false;
+        for (idx, variant) in rule.variants.iter().enumerate() {
+            self.trail.push(Event::Start(rule, idx, offset));
+            let found = self.sequence(&variant.items, offset, &mut |this, end| {
+                matched = true;
+                this.trail.push(Event::End(end));
+                if k(this, end) {
+                    return true;
+                }
+                this.trail.pop();
+                false
+            });
+
+            if found {
+                return true;
+            }
+            self.trail.pop();
+        }
+
+        if !matched {
+            self.furthest.expect(offset, || format!("<{}>", rule.name));
+        }
+        false
+    }
+
+    /// Match any of the variants and then the continuation
+    fn variants(
+        &mut self,
+        variants: &'e [RuleVariant],
+        offset: usize,
+        k: &mut Cont<'_, 'e, 'd>,
+    ) -> bool {
+        variants
+            .iter()
+            .any(|variant| self.sequence(&variant.items, offset, k))
+    }
+
+    /// Match all the atoms (in order) and then the continuation
+    fn sequence(&mut self, items: &'e [Atom], offset: usize, k: &mut Cont<'_, 'e, 'd>) -> bool {
+        match items {
+            [] => k(self, offset),
+            [first, rest @ ..] => {
+                self.atom(first, offset, &mut |this, end| this.sequence(rest, end, k))
+            }
+        }
+    }
+
+    /// Match a single atom and then the continuation
+    fn atom(&mut self, atom: &'e Atom, offset: usize, k: &mut Cont<'_, 'e, 'd>) -> bool {
+        let data = &self.data[offset..];
+        match atom {
+            Atom::Terminal { content } if data.starts_with(content.as_str()) => {
+                k(self, offset + content.len())
+            }
+            Atom::CharClass { class } => match data.chars().next() {
+                Some(c) if class.contains(c) => k(self, offset + c.len_utf8()),
+                _ => {
+                    self.furthest.expect(offset, || Furthest::atom(atom));
+                    false
+                }
+            },
+            Atom::Terminal { .. } => {
+                self.furthest.expect(offset, || Furthest::atom(atom));
+                false
+            }
+            Atom::NonTerminal { name } => self.rule(&self.engine.tree[name], offset, k),
+            Atom::Group { variants } => self.variants(variants, offset, k),
+            // The longest alternative goes first, like in the other matcher
+            Atom::Optional { atom } => self.atom(atom, offset, k) || k(self, offset),
+            Atom::ZeroOrMore { atom } => self.repeat(atom, offset, k),
+            Atom::OneOrMore { atom } => {
+                self.atom(atom, offset, &mut |this, end| this.repeat(atom, end, k))
+            }
+        }
+    }
+
+    /// Match the atom zero or more times and then the continuation
+    fn repeat(&mut self, atom: &'e Atom, offset: usize, k: &mut Cont<'_, 'e, 'd>) -> bool {
+        // An iteration that matches the empty string does not change anything, skip it so
+        // this does not loop forever
+        self.atom(atom, offset, &mut |this, end| {
+            end > offset && this.repeat(atom, end, k)
+        }) || k(self, offset)
+    }
+}
@@ -0,0 +1,360 @@
+//! Counting the derivations of each length, and sampling them uniformly, see [`Counter`]
+
+use super::atoms::{Atoms, Part};
+use super::{Engine, GenerateError};
+use crate::ast::Rule;
+use num_bigint::{BigUint, RandBigInt};
+use num_traits::{One, Zero};
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap};
+
+/// The amount of derivations of each length (in characters, the index), from 0 up to the
+/// maximum length
+pub(super) type Counts = Vec<BigUint>;
+
+/// Counts how many derivations of each length every rule has, so they can be chosen uniformly
+/// (every derivation of the requested length is equally likely, so every string is equally
+/// likely too if the grammar is not ambiguous). The weights of the variants are ignored.
+///
+/// A character class counts as one derivation per character, and the iterations of a
+/// repetition must not be empty (like when matching), except for the first one of a `+`
+///
+/// Only the rules reachable from the requested one are counted. The counts are a fixed point:
+/// all of them start at zero, and on each pass the counts of every variant are computed from
+/// the counts found so far. If a rule can derive itself
+/// without adding any character, it has infinitely many derivations and the counts never stop
+/// growing
+///
+/// Once stable, the counts of every atom (and of the atoms after it) are kept, so sampling does
+/// not compute them again
+pub(super) struct Counter<'e> {
+    /// The atoms of the reachable rules, the ones below are kept by their index
+    atoms: Atoms<'e>,
+    max_len: usize,
+    rules: BTreeMap<&'e str, Counts>,
+    /// The characters of each class
+    classes: HashMap<usize, Vec<char>>,
+    /// The counts of each atom
+    counts: HashMap<usize, Counts>,
+    /// The counts of each atom followed by the rest of its sequence
+    suffixes: HashMap<usize, Counts>,
+    /// The counts of the iterations of each repeated atom (by the index of the inner atom)
+    stars: HashMap<usize, Counts>,
+    /// The counts of the empty sequence
+    nothing: Counts,
+}
+
+impl<'e> Counter<'e> {
+    pub(super) fn new(
+        engine: &'e Engine,
+        start: &Rule,
+        max_len: usize,
+    ) -> Result<Self, GenerateError> {
+        let mut counter = Self {
+            atoms: Atoms::new(&engine.reachable(&start.name)),
+            max_len,
+            rules: BTreeMap::new(),
+            classes: HashMap::new(),
+            counts: HashMap::new(),
+            suffixes: HashMap::new(),
+            stars: HashMap::new(),
+            nothing: Vec::new(),
+        };
+        counter.nothing = counter.only(0);
+
+        for (id, part) in counter.atoms.parts.iter().enumerate() {
+            if let Part::Class(class) = part {
+                counter.classes.insert(id, class.chars().collect());
+            }
+        }
+        let names: Vec<&'e str> = counter.atoms.rules.keys().copied().collect();
+        for name in names.iter() {
+            counter.rules.insert(name, counter.zero());
+        }
+
+        // Each length needs (at most) a pass per rule to be stable, after the shorter ones
+        let passes = (max_len + 1) * (names.len() + 1) + 1;
+        for _ in 0..passes {
+            let mut changed = false;
+            for name in names.iter() {
+                let counts = counter.variants(&counter.atoms.rules[name]);
+                if counter.rules[name] != counts {
+                    counter.rules.insert(name, counts);
+                    changed = true;
+                }
+            }
+
+            if !changed {
+                counter.keep_counts();
+                return Ok(counter);
+            }
+        }
+
+        // Still growing, find one of the rules that does
+        let growing = names.iter().find(|name| {
+            let counts = counter.variants(&counter.atoms.rules[**name]);
+            counter.rules[**name] != counts
+        });
+        let growing = growing.map(|name| name.to_string()).unwrap_or_default();
+        Err(GenerateError::InfiniteDerivations(growing))
+    }
+
+    /// The derivations of each length of the rule (reachable from the requested one)
+    pub(super) fn rule(&self, name: &str) -> &Counts {
+        &self.rules[name]
+    }
+
+    /// Keep the counts of every atom, of the iterations of the repeated ones, and of every
+    /// suffix of every sequence, see [`Counter::counts`]
+    fn keep_counts(&mut self) {
+        // The inner atoms come first
+        for (id, part) in self.atoms.parts.iter().enumerate() {
+            let counts = self.atom(id);
+            if let Part::ZeroOrMore(inner) | Part::OneOrMore(inner) = part {
+                self.stars.insert(*inner, self.star(&self.counts[inner]));
+            }
+            self.counts.insert(id, counts);
+        }
+
+        for items in self.atoms.sequences() {
+            let mut rest = self.only(0);
+            for id in items.iter().rev() {
+                rest = self.concat(&self.counts[id], &rest);
+                self.suffixes.insert(*id, rest.clone());
+            }
+        }
+    }
+
+    /// The kept counts of the sequence
+    fn suffix(&self, items: &[usize]) -> &Counts {
+        match items.first() {
+            Some(first) => &self.suffixes[first],
+            None => &self.nothing,
+        }
+    }
+
+    fn zero(&self) -> Counts {
+        vec![BigUint::zero(); self.max_len + 1]
+    }
+
+    /// A single derivation of the given length
+    fn only(&self, len: usize) -> Counts {
+        let mut counts = self.zero();
+        if len <= self.max_len {
+            counts[len] = BigUint::one();
+        }
+        counts
+    }
+
+    /// The derivations of `first` followed by `second`
+    fn concat(&self, first: &Counts, second: &Counts) -> Counts {
+        let mut counts = self.zero();
+        for (flen, fcount) in first.iter().enumerate().filter(|(_, c)| !c.is_zero()) {
+            for (slen, scount) in second[..=self.max_len - flen].iter().enumerate() {
+                counts[flen + slen] += fcount * scount;
+            }
+        }
+        counts
+    }
+
+    fn variants(&self, variants: &[Vec<usize>]) -> Counts {
+        let mut counts = self.zero();
+        for items in variants {
+            for (total, count) in counts.iter_mut().zip(self.sequence(items)) {
+                *total += count;
+            }
+        }
+        counts
+    }
+
+    fn sequence(&self, items: &[usize]) -> Counts {
+        items.iter().fold(self.only(0), |counts, id| {
+            self.concat(&counts, &self.atom(*id))
+        })
+    }
+
+    fn atom(&self, id: usize) -> Counts {
+        match &self.atoms.parts[id] {
+            Part::Terminal(content) => self.only(content.chars().count()),
+            Part::Class(_) => {
+                let mut counts = self.zero();
+                if self.max_len >= 1 {
+                    counts[1] = self.classes[&id].len().into();
+                }
+                counts
+            }
+            Part::NonTerminal(name) => self.rules[name].clone(),
+            Part::Optional(inner) => {
+                let mut counts = self.atom(*inner);
+                counts[0] += 1u32;
+                counts
+            }
+            Part::ZeroOrMore(inner) => self.star(&self.atom(*inner)),
+            Part::OneOrMore(inner) => {
+                let counts = self.atom(*inner);
+                self.concat(&counts, &self.star(&counts))
+            }
+            Part::Group(variants) => self.variants(variants),
+        }
+    }
+
+    /// Zero or more non-empty iterations of the given derivations
+    fn star(&self, inner: &Counts) -> Counts {
+        let mut counts = self.only(0);
+        for len in 1..=self.max_len {
+            for first in 1..=len {
+                let count = &inner[first] * &counts[len - first];
+                counts[len] += count;
+            }
+        }
+        counts
+    }
+
+    /// Choose an index, with a probability proportional to its weight, None if all the
+    /// weights are zero
+    fn pick<R: Rng + ?Sized>(weights: &[BigUint], rng: &mut R) -> Option<usize> {
+        let total: BigUint = weights.iter().sum();
+        if total.is_zero() {
+            return None;
+        }
+
+        let mut pick = rng.gen_biguint_below(&total);
+        for (idx, weight) in weights.iter().enumerate() {
+            if pick < *weight {
+                return Some(idx);
+            }
+            pick -= weight;
+        }
+        unreachable!("The pick is lower than the total")
+    }
+
+    /// Generate a string of exactly the given length, chosen uniformly among the derivations
+    /// of the rule, None if there is none
+    /// If the rule is ambiguous, the strings with more derivations are more likely
+    pub(super) fn sample<R: Rng + ?Sized>(
+        &self,
+        rule: &Rule,
+        len: usize,
+        rng: &mut R,
+    ) -> Option<String> {
+        if len > self.max_len || self.rules[rule.name.as_str()][len].is_zero() {
+            return None;
+        }
+
+        let mut res = String::new();
+        self.sample_variants(&self.atoms.rules[rule.name.as_str()], len, rng, &mut res);
+        Some(res)
+    }
+
+    fn sample_variants<R: Rng + ?Sized>(
+        &self,
+        variants: &[Vec<usize>],
+        len: usize,
+        rng: &mut R,
+        res: &mut String,
+    ) {
+        let weights: Vec<_> = variants
+            .iter()
+            .map(|items| self.suffix(items)[len].clone())
+            .collect();
+        let idx = Self::pick(&weights, rng).expect("There are derivations of this length");
+        self.sample_sequence(&variants[idx], len, rng, res);
+    }
+
+    fn sample_sequence<R: Rng + ?Sized>(
+        &self,
+        items: &[usize],
+        len: usize,
+        rng: &mut R,
+        res: &mut String,
+    ) {
+        let [first, rest @ ..] = items else {
+            return;
+        };
+
+        // How long is the first atom
+        let (counts, rest_counts) = (&self.counts[first], self.suffix(rest));
+        let weights: Vec<_> = (0..=len)
+            .map(|flen| &counts[flen] * &rest_counts[len - flen])
+            .collect();
+        let flen = Self::pick(&weights, rng).expect("There are derivations of this length");
+
+        self.sample_atom(*first, flen, rng, res);
+        self.sample_sequence(rest, len - flen, rng, res);
+    }
+
+    fn sample_atom<R: Rng + ?Sized>(&self, id: usize, len: usize, rng: &mut R, res: &mut String) {
+        match &self.atoms.parts[id] {
+            Part::Terminal(content) => *res += content,
+            Part::Class(_) => {
+                let chars = &self.classes[&id];
+                res.push(chars[rng.gen_range(0..chars.len())]);
+            }
+            Part::NonTerminal(name) => {
+                self.sample_variants(&self.atoms.rules[name], len, rng, res);
+            }
+            Part::Optional(inner) => {
+                // Either nothing (only if empty) or the atom
+                let counts = &self.counts[inner];
+                let nothing = BigUint::from((len == 0) as u32);
+                if Self::pick(&[nothing, counts[len].clone()], rng) == Some(1) {
+                    self.sample_atom(*inner, len, rng, res);
+                }
+            }
+            Part::ZeroOrMore(inner) => self.sample_star(*inner, len, rng, res),
+            Part::OneOrMore(inner) => {
+                let (counts, star) = (&self.counts[inner], &self.stars[inner]);
+                let weights: Vec<_> = (0..=len)
+                    .map(|first| &counts[first] * &star[len - first])
+                    .collect();
+                let first = Self::pick(&weights, rng).expect("There are derivations");
+                self.sample_atom(*inner, first, rng, res);
+                self.sample_star(*inner, len - first, rng, res);
+            }
+            Part::Group(variants) => self.sample_variants(variants, len, rng, res),
+        }
+    }
+
+    /// Zero or more non-empty iterations of the atom, see [`Counter::star`]
+    fn sample_star<R: Rng + ?Sized>(&self, id: usize, len: usize, rng: &mut R, res: &mut String) {
+        let (counts, star) = (&self.counts[&id], &self.stars[&id]);
+
+        let mut left = len;
+        while left > 0 {
+            let weights: Vec<_> = (0..=left)
+                .map(|first| match first {
+                    0 => BigUint::zero(),
+                    _ => &counts[first] * &star[left - first],
+                })
+                .collect();
+            let first = Self::pick(&weights, rng).expect("There are derivations");
+            self.sample_atom(id, first, rng, res);
+            left -= first;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast;
+    use crate::engine::{Algorithm, Engine, MatchOptions};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn uniform_samples_have_the_length() {
+        let grammar = "<s> ::= (\"a\" | \"bc\")+ [0-9]? | \"(\" <s>* \")\"\n";
+        let engine = Engine::build(&ast::parse(grammar).unwrap(), false).unwrap();
+        let opts = MatchOptions {
+            full: true,
+            algorithm: Algorithm::Earley,
+            ..Default::default()
+        };
+
+        let mut rng = StdRng::seed_from_u64(0);
+        for sample in engine.uniform_samples("s", 9, &mut rng).unwrap().take(50) {
+            let sample = sample.unwrap();
+            assert_eq!(sample.chars().count(), 9);
+            assert!(engine.match_tree("s", &sample, &opts).is_ok(), "{sample}");
+        }
+    }
+}
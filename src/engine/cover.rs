@@ -0,0 +1,443 @@
+//! Generation of a small set of strings that use every variant of the rules, see [`Cover`]
+
+use super::{Coverage, Engine};
+use crate::ast::{Atom, Rule, RuleVariant};
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+
+/// Where some variants are written: a rule, or one of the groups inside of a rule (numbered
+/// in the order they are written, see [`Cover::groups`])
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Place<'e> {
+    Rule(&'e str),
+    Group(&'e str, usize),
+}
+
+/// Generates strings that go (by the shortest way) to the variants that no string used yet (of
+/// the rules and of the groups), until all of them are used. Each time a rule or a group is
+/// generated on the way:
+///  * If it has a pending variant, that one is chosen (and it stops being pending)
+///  * Otherwise, the variant closest to a pending one is chosen
+///
+/// The atoms of the chosen variant that are closer than the rule (or group) go on towards a
+/// pending variant, and once a pending variant was used (by that variant or before it), every
+/// atom that can still reach one does too. The other ones are generated by their shortest
+/// derivations
+///
+/// The optional atoms are generated once on the way to a pending variant, and the repetitions
+/// as long as each time uses a new one, otherwise they are skipped (or generated once, for the
+/// `+`)
+///
+/// On the way to a pending variant, either the distances get strictly shorter or a pending
+/// variant was used, and the heights get strictly lower on the shortest derivations, so every
+/// string ends, and it uses at least one pending variant
+pub(super) struct Cover<'e, 'r, R: ?Sized> {
+    engine: &'e Engine,
+    rng: &'r mut R,
+    /// The groups inside of each rule that can be reached, the outer ones before the ones
+    /// inside of them
+    groups: BTreeMap<&'e str, Vec<&'e [RuleVariant]>>,
+    /// The variants (place and index) that no string used yet, only the ones that can
+    /// generate something
+    pending: BTreeSet<(Place<'e>, usize)>,
+    /// The variants that cannot generate any (finite) string
+    impossible: BTreeSet<(Place<'e>, usize)>,
+    /// How many rules (or groups) must be generated (from each rule) to reach a pending
+    /// variant, 0 if the rule has one, the rules that cannot reach any are not here
+    dist: BTreeMap<&'e str, usize>,
+    /// The rules that use each rule, their distances may change with the ones of the rule
+    users: BTreeMap<&'e str, BTreeSet<&'e str>>,
+}
+
+impl<'e, 'r, R: Rng + ?Sized> Cover<'e, 'r, R> {
+    /// Only the rules reachable from `start` are covered
+    pub(super) fn new(engine: &'e Engine, start: &'e Rule, rng: &'r mut R) -> Self {
+        let mut cover = Self {
+            engine,
+            rng,
+            groups: BTreeMap::new(),
+            pending: BTreeSet::new(),
+            impossible: BTreeSet::new(),
+            dist: BTreeMap::new(),
+            users: BTreeMap::new(),
+        };
+
+        for rule in engine.reachable(&start.name) {
+            let name = rule.name.as_str();
+            let mut groups = Vec::new();
+            for atom in rule
+                .variants
+                .iter()
+                .flat_map(|variant| variant.items.iter())
+            {
+                atom.for_each_atom(&mut |atom| {
+                    if let Atom::Group { variants } = atom {
+                        groups.push(variants.as_slice());
+                    }
+                });
+                atom.for_each_non_terminal(&mut |used| {
+                    cover.users.entry(used).or_default().insert(name);
+                });
+            }
+
+            cover.add_variants(Place::Rule(name), &rule.variants);
+            for (idx, variants) in groups.iter().enumerate() {
+                cover.add_variants(Place::Group(name, idx), variants);
+            }
+            cover.groups.insert(name, groups);
+        }
+
+        let names = cover.groups.keys().copied().collect();
+        cover.find_distances(names);
+        cover
+    }
+
+    /// Add the variants to the pending (or the impossible) ones
+    fn add_variants(&mut self, place: Place<'e>, variants: &[RuleVariant]) {
+        for (idx, variant) in variants.iter().enumerate() {
+            if self.is_productive(variant) {
+                self.pending.insert((place, idx));
+            } else {
+                self.impossible.insert((place, idx));
+            }
+        }
+    }
+
+    /// Generate strings until no pending variant can be reached from the rule
+    pub(super) fn run(mut self, start: &'e Rule) -> Coverage {
+        let mut samples = Vec::new();
+        while self.dist.contains_key(start.name.as_str()) {
+            let mut res = String::new();
+            self.rule(start, &mut res);
+            samples.push(res);
+        }
+
+        let mut uncovered: Vec<_> = self
+            .pending
+            .union(&self.impossible)
+            .map(|(place, idx)| match place {
+                Place::Rule(name) => (name.to_string(), None, *idx),
+                Place::Group(name, group) => (name.to_string(), Some(*group), *idx),
+            })
+            .collect();
+        uncovered.sort();
+
+        Coverage { samples, uncovered }
+    }
+
+    /// Compute [`Cover::dist`] again for the given rules, the other ones must not depend on
+    /// them (so their distances are still right)
+    /// Like Dijkstra, the rules are settled from the closest to the furthest (a rule is always
+    /// further than the rules it goes through), and a rule is only checked again when one of
+    /// the rules it uses is settled
+    fn find_distances(&mut self, names: BTreeSet<&'e str>) {
+        for name in names.iter() {
+            self.dist.remove(name);
+        }
+
+        let mut queue = BinaryHeap::new();
+        for name in names.iter() {
+            if let Some(dist) = self.rule_dist(name) {
+                queue.push(Reverse((dist, *name)));
+            }
+        }
+
+        while let Some(Reverse((dist, name))) = queue.pop() {
+            if self.dist.contains_key(name) {
+                continue;
+            }
+            self.dist.insert(name, dist);
+
+            for user in self.users.get(name).into_iter().flatten() {
+                if !names.contains(user) || self.dist.contains_key(user) {
+                    continue;
+                }
+                if let Some(dist) = self.rule_dist(user) {
+                    queue.push(Reverse((dist, *user)));
+                }
+            }
+        }
+    }
+
+    /// The rule and every rule that uses it (directly or not)
+    fn users_of(&self, name: &'e str) -> BTreeSet<&'e str> {
+        let mut found = BTreeSet::from([name]);
+        let mut stack = vec![name];
+        while let Some(name) = stack.pop() {
+            for user in self.users.get(name).into_iter().flatten() {
+                if found.insert(user) {
+                    stack.push(user);
+                }
+            }
+        }
+        found
+    }
+
+    /// The distance to a pending variant from the rule, given the distances known so far
+    fn rule_dist(&self, name: &'e str) -> Option<usize> {
+        let rule = &self.engine.tree[name];
+        self.place_dist(Place::Rule(name), name, &rule.variants)
+    }
+
+    /// Whether the variant can generate some string
+    fn is_productive(&self, variant: &RuleVariant) -> bool {
+        Engine::variant_height(variant, &self.engine.min_height).is_some()
+    }
+
+    /// The index of the first pending variant of the place
+    fn first_pending(&self, place: Place<'e>, variants: &[RuleVariant]) -> Option<usize> {
+        (0..variants.len()).find(|idx| self.pending.contains(&(place, *idx)))
+    }
+
+    /// Where the group (inside of the given rule) is
+    fn group(&self, rule: &'e str, variants: &[RuleVariant]) -> Place<'e> {
+        let idx = self.groups[rule]
+            .iter()
+            .position(|group| std::ptr::eq(*group, variants))
+            .expect("The groups of the reachable rules are known");
+        Place::Group(rule, idx)
+    }
+
+    /// The distance to a pending variant from the variants of the place (inside of the given
+    /// rule), 0 if one of them is pending
+    fn place_dist(
+        &self,
+        place: Place<'e>,
+        rule: &'e str,
+        variants: &[RuleVariant],
+    ) -> Option<usize> {
+        if self.first_pending(place, variants).is_some() {
+            return Some(0);
+        }
+
+        variants
+            .iter()
+            .filter(|variant| self.is_productive(variant))
+            .filter_map(|variant| self.variant_dist(rule, variant))
+            .min()
+            .map(|dist| dist + 1)
+    }
+
+    /// The distance to a pending variant through any of the atoms of the variant
+    fn variant_dist(&self, rule: &'e str, variant: &RuleVariant) -> Option<usize> {
+        variant
+            .items
+            .iter()
+            .filter_map(|atom| self.atom_dist(rule, atom))
+            .min()
+    }
+
+    /// The distance to a pending variant through the atom
+    fn atom_dist(&self, rule: &'e str, atom: &Atom) -> Option<usize> {
+        match atom {
+            Atom::Terminal { .. } | Atom::CharClass { .. } => None,
+            Atom::NonTerminal { name } => self.dist.get(name.as_str()).copied(),
+            Atom::Optional { atom } | Atom::ZeroOrMore { atom } | Atom::OneOrMore { atom } => {
+                self.atom_dist(rule, atom)
+            }
+            Atom::Group { variants } => self.place_dist(self.group(rule, variants), rule, variants),
+        }
+    }
+
+    /// Whether the atom goes towards a pending variant: if it is closer to one than `limit`,
+    /// or if it can reach one and some variant was used since there were `before` pending
+    fn is_routed(&self, rule: &'e str, atom: &Atom, limit: usize, before: usize) -> bool {
+        self.atom_dist(rule, atom)
+            .is_some_and(|dist| dist < limit || self.pending.len() < before)
+    }
+
+    /// Choose the productive variant closest to a pending one (there must be one)
+    fn closest<'v>(&self, rule: &'e str, variants: &'v [RuleVariant]) -> &'v RuleVariant {
+        variants
+            .iter()
+            .filter(|variant| self.is_productive(variant))
+            .filter_map(|variant| Some((self.variant_dist(rule, variant)?, variant)))
+            .min_by_key(|(dist, _)| *dist)
+            .map(|(_, variant)| variant)
+            .expect("A pending variant can be reached")
+    }
+
+    /// Mark the variant of the place as used, only the distances of the rules that use its
+    /// rule (or the rule itself) may change
+    fn use_variant(&mut self, place: Place<'e>, idx: usize) {
+        if self.pending.remove(&(place, idx)) {
+            // The other rules only depend on the distance of the rule
+            let (Place::Rule(rule) | Place::Group(rule, _)) = place;
+            if self.rule_dist(rule) != self.dist.get(rule).copied() {
+                self.find_distances(self.users_of(rule));
+            }
+        }
+    }
+
+    /// Generate a string for the rule (which can reach a pending variant) towards a pending
+    /// variant, and push it to `res`
+    fn rule(&mut self, rule: &'e Rule, res: &mut String) {
+        let limit = self.dist[rule.name.as_str()];
+        self.variants(
+            Place::Rule(&rule.name),
+            &rule.name,
+            &rule.variants,
+            limit,
+            res,
+        );
+    }
+
+    /// Choose the pending variant of the place (or the one closest to a pending variant),
+    /// and generate it, `limit` is the distance of the place to a pending variant
+    fn variants(
+        &mut self,
+        place: Place<'e>,
+        rule: &'e str,
+        variants: &'e [RuleVariant],
+        limit: usize,
+        res: &mut String,
+    ) {
+        let before = self.pending.len();
+        let variant = match self.first_pending(place, variants) {
+            Some(idx) => {
+                self.use_variant(place, idx);
+                &variants[idx]
+            }
+            None => self.closest(rule, variants),
+        };
+
+        for atom in variant.items.iter() {
+            // The distances may have changed, if a pending variant was used on the way
+            if self.is_routed(rule, atom, limit, before) {
+                self.atom(rule, atom, limit, before, res);
+            } else {
+                self.shortest(rule, atom, res);
+            }
+        }
+    }
+
+    /// Generate a string for the atom (inside of the given rule, and routed, see
+    /// [`Cover::is_routed`]) towards a pending variant, and push it to `res`
+    fn atom(
+        &mut self,
+        rule: &'e str,
+        atom: &'e Atom,
+        limit: usize,
+        before: usize,
+        res: &mut String,
+    ) {
+        match atom {
+            Atom::Terminal { .. } | Atom::CharClass { .. } => self.shortest(rule, atom, res),
+            Atom::NonTerminal { name } => self.rule(&self.engine.tree[name], res),
+            Atom::Optional { atom } => self.atom(rule, atom, limit, before, res),
+            Atom::ZeroOrMore { atom: inner } | Atom::OneOrMore { atom: inner } => {
+                // Repeat while each time uses a new pending variant
+                let mut left = self.pending.len();
+                loop {
+                    self.atom(rule, inner, limit, before, res);
+                    if self.pending.len() == left || !self.is_routed(rule, inner, limit, before) {
+                        break;
+                    }
+                    left = self.pending.len();
+                }
+            }
+            Atom::Group { variants } => {
+                let place = self.group(rule, variants);
+                let limit = self
+                    .place_dist(place, rule, variants)
+                    .expect("The routed atoms can reach a pending variant");
+                self.variants(place, rule, variants, limit, res);
+            }
+        }
+    }
+
+    /// The index of the productive variant with the minimum height
+    fn lowest(&self, variants: &[RuleVariant]) -> usize {
+        variants
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, variant)| {
+                let height = Engine::variant_height(variant, &self.engine.min_height)?;
+                Some((height, idx))
+            })
+            .min()
+            .map(|(_, idx)| idx)
+            .expect("Only the productive rules are generated")
+    }
+
+    /// Generate the shortest derivation (the one with the minimum height) of the atom (inside
+    /// of the given rule), and push it to `res`, the pending variants used on the way stop
+    /// being pending
+    fn shortest(&mut self, rule: &'e str, atom: &'e Atom, res: &mut String) {
+        match atom {
+            Atom::Terminal { content } => *res += content,
+            Atom::CharClass { class } => res.push(Engine::gen_random_char(class, self.rng)),
+            Atom::NonTerminal { name } => {
+                let rule = &self.engine.tree[name];
+                let idx = self.lowest(&rule.variants);
+                self.use_variant(Place::Rule(&rule.name), idx);
+                for atom in rule.variants[idx].items.iter() {
+                    self.shortest(&rule.name, atom, res);
+                }
+            }
+            Atom::Optional { .. } | Atom::ZeroOrMore { .. } => {}
+            Atom::OneOrMore { atom } => self.shortest(rule, atom, res),
+            Atom::Group { variants } => {
+                let idx = self.lowest(variants);
+                self.use_variant(self.group(rule, variants), idx);
+                for atom in variants[idx].items.iter() {
+                    self.shortest(rule, atom, res);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast;
+    use crate::engine::{Algorithm, Engine, MatchOptions};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn left_recursive_rule_ends() {
+        let tree = ast::parse("<r> ::= <r> <p> | \"z\"\n<p> ::= \"q\" | \"w\"\n").unwrap();
+        let engine = Engine::build(&tree, false).unwrap();
+        let opts = MatchOptions {
+            full: true,
+            algorithm: Algorithm::Earley,
+            packrat: false,
+        };
+
+        for seed in 0..20 {
+            let coverage = engine.cover("r", &mut StdRng::seed_from_u64(seed)).unwrap();
+            assert!(coverage.uncovered.is_empty());
+            for sample in coverage.samples.iter() {
+                assert!(engine.match_tree("r", sample, &opts).is_ok(), "{sample:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn every_slot_covers_a_new_variant() {
+        let engine = Engine::build(
+            &ast::parse(include_str!("../../example.bnf")).unwrap(),
+            false,
+        );
+        let coverage = engine.unwrap().cover("date", &mut StdRng::seed_from_u64(0));
+        let coverage = coverage.unwrap();
+        assert!(coverage.uncovered.is_empty());
+        assert!(coverage.samples.len() <= 4, "{:?}", coverage.samples);
+    }
+
+    #[test]
+    fn group_variants_are_covered() {
+        let grammar = "<b> ::= \"q\" (\"r\" | \"s\")? (\"t\" | <bad>)\n<bad> ::= \"x\" <bad>\n";
+        let engine = Engine::build(&ast::parse(grammar).unwrap(), false).unwrap();
+        let coverage = engine.cover("b", &mut StdRng::seed_from_u64(0)).unwrap();
+
+        let mut samples = coverage.samples.clone();
+        samples.sort();
+        assert_eq!(samples, ["qrt", "qst"]);
+        assert_eq!(
+            coverage.uncovered,
+            [("b".to_string(), Some(1), 1), ("bad".to_string(), None, 0)]
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! Finding the left-recursive rules, see [`LeftCorners`]
+
+use super::Engine;
+use crate::ast::Rule;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+/// The graph of the rules (by index), with an edge from each rule to the ones it may call
+/// without consuming any input, see [`Engine::leading_non_terminals`]. Each edge keeps the
+/// nullable rules skipped before the call, so the cycles show the whole chain of rules
+///
+/// The left-recursive rules are the ones in a cycle of this graph, which are found at once
+/// with its strongly connected components (Tarjan's algorithm), in linear time
+pub(super) struct LeftCorners<'a> {
+    rules: &'a [Rule],
+    /// The rules that each rule may start with, without repetitions
+    edges: Vec<Vec<usize>>,
+    /// The nullable rules skipped before each call (by the indices of both rules)
+    skipped: BTreeMap<(usize, usize), Vec<&'a str>>,
+}
+
+impl<'a> LeftCorners<'a> {
+    /// All the non-terminals must exist, and `nullable` must be the rules that may match the
+    /// empty string
+    pub(super) fn new(rules: &'a [Rule], nullable: &BTreeSet<String>) -> Self {
+        let indices: BTreeMap<&str, usize> = rules
+            .iter()
+            .enumerate()
+            .map(|(idx, rule)| (rule.name.as_str(), idx))
+            .collect();
+
+        let mut skipped = BTreeMap::new();
+        let edges = rules
+            .iter()
+            .enumerate()
+            .map(|(idx, rule)| {
+                let mut leading = Vec::new();
+                for variant in rule.variants.iter() {
+                    let items = &variant.items;
+                    Engine::leading_non_terminals(items, nullable, &mut Vec::new(), &mut leading);
+                }
+
+                let mut edges = BTreeSet::new();
+                for (name, prefix) in leading {
+                    let next = indices[name];
+                    edges.insert(next);
+                    // The shortest way to the call, if there are many
+                    let shortest = skipped.entry((idx, next)).or_insert_with(|| prefix.clone());
+                    if prefix.len() < shortest.len() {
+                        *shortest = prefix;
+                    }
+                }
+                edges.into_iter().collect()
+            })
+            .collect();
+
+        Self {
+            rules,
+            edges,
+            skipped,
+        }
+    }
+
+    /// The strongly connected components that have a cycle (more than one rule, or a single
+    /// rule that calls itself), each one sorted, and in the order of their first rule
+    pub(super) fn cycles(&self) -> Vec<Vec<usize>> {
+        let mut tarjan = Tarjan {
+            edges: &self.edges,
+            index: vec![None; self.edges.len()],
+            low: vec![0; self.edges.len()],
+            stack: Vec::new(),
+            on_stack: vec![false; self.edges.len()],
+            visited: 0,
+            components: Vec::new(),
+        };
+        for node in 0..self.edges.len() {
+            if tarjan.index[node].is_none() {
+                tarjan.visit(node);
+            }
+        }
+
+        let mut cycles: Vec<_> = tarjan
+            .components
+            .into_iter()
+            .filter(|component| match component[..] {
+                [node] => self.edges[node].contains(&node),
+                _ => true,
+            })
+            .map(|mut component| {
+                component.sort();
+                component
+            })
+            .collect();
+        cycles.sort();
+        cycles
+    }
+
+    /// The names of the rules in the shortest cycle from `start` back to itself, `component`
+    /// is the (sorted) strongly connected component of `start`
+    pub(super) fn cycle(&self, start: usize, component: &[usize]) -> Vec<&'a str> {
+        // Breadth-first, only inside of the component
+        let mut prev = BTreeMap::new();
+        let mut queue = VecDeque::from([start]);
+        let last = 'search: loop {
+            let node = queue.pop_front().expect("The component has a cycle");
+            for next in self.edges[node].iter().copied() {
+                if next == start {
+                    break 'search node;
+                }
+                if component.binary_search(&next).is_ok() && !prev.contains_key(&next) {
+                    prev.insert(next, node);
+                    queue.push_back(next);
+                }
+            }
+        };
+
+        let mut nodes = vec![start, last];
+        while nodes[nodes.len() - 1] != start {
+            nodes.push(prev[&nodes[nodes.len() - 1]]);
+        }
+        nodes.reverse();
+
+        // With the nullable rules skipped on each call
+        let mut path = vec![self.rules[start].name.as_str()];
+        for pair in nodes.windows(2) {
+            path.extend(self.skipped[&(pair[0], pair[1])].iter().copied());
+            path.push(&self.rules[pair[1]].name);
+        }
+        path
+    }
+}
+
+/// The state of Tarjan's algorithm
+struct Tarjan<'g> {
+    edges: &'g [Vec<usize>],
+    /// The order in which each node was visited, None if not yet
+    index: Vec<Option<usize>>,
+    /// The lowest index reachable from each node (through the nodes still in the stack)
+    low: Vec<usize>,
+    /// The visited nodes whose component is not known yet
+    stack: Vec<usize>,
+    on_stack: Vec<bool>,
+    /// How many nodes were visited
+    visited: usize,
+    /// The components found so far
+    components: Vec<Vec<usize>>,
+}
+
+impl Tarjan<'_> {
+    fn visit(&mut self, node: usize) {
+        let index = self.visited;
+        self.visited += 1;
+        self.index[node] = Some(index);
+        self.low[node] = index;
+        self.stack.push(node);
+        self.on_stack[node] = true;
+
+        for next in self.edges[node].iter().copied() {
+            match self.index[next] {
+                None => {
+                    self.visit(next);
+                    self.low[node] = self.low[node].min(self.low[next]);
+                }
+                Some(next_index) if self.on_stack[next] => {
+                    self.low[node] = self.low[node].min(next_index);
+                }
+                Some(_) => {}
+            }
+        }
+
+        // The root of a component, all the nodes above it in the stack are part of it
+        if self.low[node] == index {
+            let mut component = Vec::new();
+            loop {
+                let top = self.stack.pop().expect("The node is in the stack");
+                self.on_stack[top] = false;
+                component.push(top);
+                if top == node {
+                    break;
+                }
+            }
+            self.components.push(component);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast;
+    use crate::engine::Engine;
+
+    #[test]
+    fn every_endless_cycle_is_reported() {
+        let grammar = "<a> ::= <m> <n> <a> \"x\" | <m> <a> \"y\"\n\
+                       <m> ::= \"m\" | ε\n\
+                       <n> ::= ε\n\
+                       <d> ::= <e> \"q\"\n\
+                       <e> ::= <n> <d>\n";
+        let err = Engine::build(&ast::parse(grammar).unwrap(), false)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Some rules may cause an infinite recursion: a -> m -> a, d -> e -> n -> d"
+        );
+    }
+}
@@ -0,0 +1,481 @@
+//! An Earley parser, works with any context-free grammar (including the ambiguous and the
+//! left-recursive ones) in at most cubic time, see [`Chart`]
+
+use super::{Engine, Failure, Furthest, MatchResult, Node};
+use crate::ast::{Atom, CharClass, RuleVariant};
+use std::collections::{BTreeMap, HashSet};
+use std::rc::Rc;
+
+/// A symbol of a [`Production`]
+#[derive(Debug, Clone, Copy)]
+enum Symbol<'e> {
+    Terminal(&'e str),
+    Class(&'e CharClass),
+    NonTerminal(usize),
+}
+
+/// A flat production: `lhs ::= symbols...`
+#[derive(Debug)]
+struct Production<'e> {
+    lhs: usize,
+    /// The index of the variant (in its rule or group) this production comes from
+    variant: usize,
+    symbols: Vec<Symbol<'e>>,
+}
+
+/// The rules of the engine, translated to flat productions
+/// The named rules get the first non-terminals, and every group or repetition gets an
+/// anonymous one:
+///  * `( a | b )` is `G ::= a | b`
+///  * `a?` is `O ::= a | ""`
+///  * `a*` is `S ::= S a | ""`
+///  * `a+` is `P ::= P a | a`
+struct Grammar<'e> {
+    /// The index of each named rule
+    index: BTreeMap<&'e str, usize>,
+    /// The name of each non-terminal (None for the anonymous ones)
+    names: Vec<Option<&'e str>>,
+    prods: Vec<Production<'e>>,
+    /// The productions of each non-terminal
+    by_lhs: Vec<Vec<usize>>,
+    /// Whether each non-terminal may derive the empty string
+    nullable: Vec<bool>,
+    /// For each nullable non-terminal, a production that derives the empty string
+    null_prod: Vec<Option<usize>>,
+}
+
+impl<'e> Grammar<'e> {
+    fn new(engine: &'e Engine) -> Self {
+        let mut grammar = Self {
+            index: BTreeMap::new(),
+            names: Vec::new(),
+            prods: Vec::new(),
+            by_lhs: Vec::new(),
+            nullable: Vec::new(),
+            null_prod: Vec::new(),
+        };
+
+        for name in engine.tree.keys() {
+            let nt = grammar.add_non_terminal(Some(name));
+            grammar.index.insert(name, nt);
+        }
+
+        for (name, rule) in engine.tree.iter() {
+            grammar.add_variants(grammar.index[name.as_str()], &rule.variants);
+        }
+
+        grammar.find_nullable();
+        grammar
+    }
+
+    fn add_non_terminal(&mut self, name: Option<&'e str>) -> usize {
+        self.names.push(name);
+        self.by_lhs.push(Vec::new());
+        self.names.len() - 1
+    }
+
+    fn add_production(&mut self, lhs: usize, symbols: Vec<Symbol<'e>>) {
+        let variant = self.by_lhs[lhs].len();
+        self.by_lhs[lhs].push(self.prods.len());
+        self.prods.push(Production {
+            lhs,
+            variant,
+            symbols,
+        });
+    }
+
+    fn add_variants(&mut self, lhs: usize, variants: &'e [RuleVariant]) {
+        for variant in variants {
+            let symbols = variant.items.iter().map(|a| self.symbol(a)).collect();
+            self.add_production(lhs, symbols);
+        }
+    }
+
+    /// Translate an atom, adding the anonymous non-terminals it may need
+    fn symbol(&mut self, atom: &'e Atom) -> Symbol<'e> {
+        let (nt, symbols) = match atom {
+            Atom::Terminal { content } => return Symbol::Terminal(content),
+            Atom::CharClass { class } => return Symbol::Class(class),
+            Atom::NonTerminal { name } => return Symbol::NonTerminal(self.index[name.as_str()]),
+            Atom::Group { variants } => {
+                let nt = self.add_non_terminal(None);
+                self.add_variants(nt, variants);
+                return Symbol::NonTerminal(nt);
+            }
+            Atom::Optional { atom } => {
+                let (nt, sym) = (self.add_non_terminal(None), self.symbol(atom));
+                (nt, [vec![sym], vec![]])
+            }
+            Atom::ZeroOrMore { atom } => {
+                let (nt, sym) = (self.add_non_terminal(None), self.symbol(atom));
+                (nt, [vec![Symbol::NonTerminal(nt), sym], vec![]])
+            }
+            Atom::OneOrMore { atom } => {
+                let (nt, sym) = (self.add_non_terminal(None), self.symbol(atom));
+                (nt, [vec![Symbol::NonTerminal(nt), sym], vec![sym]])
+            }
+        };
+
+        for symbols in symbols {
+            self.add_production(nt, symbols);
+        }
+        Symbol::NonTerminal(nt)
+    }
+
+    /// Compute [`Grammar::nullable`] and [`Grammar::null_prod`] (a fixed point)
+    fn find_nullable(&mut self) {
+        self.nullable = vec![false; self.names.len()];
+        self.null_prod = vec![None; self.names.len()];
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (idx, prod) in self.prods.iter().enumerate() {
+                if self.nullable[prod.lhs] {
+                    continue;
+                }
+
+                let nullable = prod.symbols.iter().all(|sym| match sym {
+                    Symbol::Terminal(term) => term.is_empty(),
+                    Symbol::Class(_) => false,
+                    Symbol::NonTerminal(nt) => self.nullable[*nt],
+                });
+
+                if nullable {
+                    self.nullable[prod.lhs] = true;
+                    self.null_prod[prod.lhs] = Some(idx);
+                    changed = true;
+                }
+            }
+        }
+    }
+}
+
+/// How an item was created, used to rebuild the derivation after recognizing the input
+/// Every link points to items created before this one, so following them always terminates
+#[derive(Debug, Clone, Copy)]
+enum Link {
+    /// The dot is at the start
+    Predicted,
+    /// Created from the given item, by scanning a terminal
+    Scanned(usize),
+    /// Created from the given item, by skipping a nullable non-terminal
+    Skipped(usize),
+    /// Created from the first item, with the second (completed) item as its non-terminal
+    Completed(usize, usize),
+}
+
+/// An Earley item: `prod` matched up to the `dot`-th symbol, from `origin` to `end`
+#[derive(Debug, Clone, Copy)]
+struct Item {
+    prod: usize,
+    dot: usize,
+    origin: usize,
+    end: usize,
+    link: Link,
+}
+
+/// The Earley chart, one set of items per offset of the input
+pub(super) struct Chart<'e, 'd> {
+    engine: &'e Engine,
+    grammar: Grammar<'e>,
+    data: &'d str,
+    items: Vec<Item>,
+    /// The items that end at each offset (indexes to `items`)
+    sets: Vec<Vec<usize>>,
+    /// To avoid adding the same (prod, dot, origin) twice to a set
+    seen: Vec<HashSet<(usize, usize, usize)>>,
+}
+
+impl<'e, 'd> Chart<'e, 'd> {
+    pub(super) fn new(engine: &'e Engine, data: &'d str) -> Self {
+        Self {
+            engine,
+            grammar: Grammar::new(engine),
+            data,
+            items: Vec::new(),
+            sets: vec![Vec::new(); data.len() + 1],
+            seen: vec![HashSet::new(); data.len() + 1],
+        }
+    }
+
+    /// Recognize the initial rule, if `full` the whole input must be matched, otherwise the
+    /// longest matching prefix is used
+    pub(super) fn run(mut self, initial: &str, full: bool) -> MatchResult {
+        let start = self.grammar.index[initial];
+        self.recognize(start, false);
+
+        // The completed initial rule that ends further
+        let last = (0..=self.data.len()).rev().find_map(|end| {
+            self.sets[end].iter().copied().find(|id| {
+                let item = &self.items[*id];
+                let prod = &self.grammar.prods[item.prod];
+                prod.lhs == start && item.origin == 0 && item.dot == prod.symbols.len()
+            })
+        });
+
+        let Some(last) = last else {
+            return Err(Failure {
+                longest: None,
+                furthest: self.furthest(),
+            });
+        };
+
+        let end = self.items[last].end;
+        if full && end < self.data.len() {
+            return Err(Failure {
+                longest: Some(end),
+                furthest: self.furthest(),
+            });
+        }
+
+        let mut outp = Vec::new();
+        self.nodes(last, &mut outp);
+        Ok(Rc::unwrap_or_clone(outp.remove(0)))
+    }
+
+    /// Recognize the initial rule starting at every offset (in a single pass), returns where
+    /// the longest match that starts at each offset ends
+    pub(super) fn search(mut self, initial: &str) -> Vec<Option<usize>> {
+        let start = self.grammar.index[initial];
+        self.recognize(start, true);
+
+        let mut ends = vec![None; self.data.len() + 1];
+        for item in self.items.iter() {
+            let prod = &self.grammar.prods[item.prod];
+            if prod.lhs == start && item.dot == prod.symbols.len() {
+                ends[item.origin] = ends[item.origin].max(Some(item.end));
+            }
+        }
+
+        ends
+    }
+
+    /// The last set with items is where the input stopped matching, what the items there are
+    /// waiting for is what was expected
+    fn furthest(&self) -> Furthest {
+        let offset = (0..self.sets.len())
+            .rev()
+            .find(|set| !self.sets[*set].is_empty())
+            .unwrap_or(0);
+        let data = &self.data[offset..];
+
+        let mut furthest = Furthest {
+            offset,
+            ..Default::default()
+        };
+        for id in self.sets[offset].iter() {
+            let item = &self.items[*id];
+            match self.grammar.prods[item.prod].symbols.get(item.dot) {
+                Some(Symbol::Terminal(term)) if !data.starts_with(term) => {
+                    furthest.expected.insert(format!("{term:?}"));
+                }
+                Some(Symbol::Class(class)) => {
+                    furthest.expected.insert(class.to_string());
+                }
+                Some(Symbol::NonTerminal(nt)) if !self.grammar.nullable[*nt] => {
+                    if let Some(name) = self.grammar.names[*nt] {
+                        furthest.expected.insert(format!("<{name}>"));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        furthest
+    }
+
+    fn add(&mut self, set: usize, prod: usize, dot: usize, origin: usize, link: Link) {
+        if !self.seen[set].insert((prod, dot, origin)) {
+            return;
+        }
+
+        self.sets[set].push(self.items.len());
+        self.items.push(Item {
+            prod,
+            dot,
+            origin,
+            end: set,
+            link,
+        });
+    }
+
+    /// Fill the sets, from left to right
+    /// If `everywhere`, the start non-terminal is predicted at every offset, not only at the
+    /// first one
+    fn recognize(&mut self, start: usize, everywhere: bool) {
+        for set in 0..self.sets.len() {
+            if set == 0 || everywhere {
+                for idx in 0..self.grammar.by_lhs[start].len() {
+                    self.add(
+                        set,
+                        self.grammar.by_lhs[start][idx],
+                        0,
+                        set,
+                        Link::Predicted,
+                    );
+                }
+            }
+
+            // The set grows while it is processed
+            let mut idx = 0;
+            while idx < self.sets[set].len() {
+                let id = self.sets[set][idx];
+                let Item {
+                    prod, dot, origin, ..
+                } = self.items[id];
+                idx += 1;
+
+                let data = &self.data[set..];
+                match self.grammar.prods[prod].symbols.get(dot).copied() {
+                    None => self.complete(id),
+                    Some(Symbol::Terminal(term)) => {
+                        if data.starts_with(term) {
+                            self.add(set + term.len(), prod, dot + 1, origin, Link::Scanned(id));
+                        }
+                    }
+                    Some(Symbol::Class(class)) => match data.chars().next() {
+                        Some(c) if class.contains(c) => {
+                            let end = set + c.len_utf8();
+                            self.add(end, prod, dot + 1, origin, Link::Scanned(id));
+                        }
+                        _ => {}
+                    },
+                    Some(Symbol::NonTerminal(nt)) => {
+                        for pidx in 0..self.grammar.by_lhs[nt].len() {
+                            let pred = self.grammar.by_lhs[nt][pidx];
+                            self.add(set, pred, 0, set, Link::Predicted);
+                        }
+
+                        // The completion of an empty non-terminal happens in this same set,
+                        // maybe before this item was added, so skip it here
+                        if self.grammar.nullable[nt] {
+                            self.add(set, prod, dot + 1, origin, Link::Skipped(id));
+                        }
+                    }
+                }
+            }
+
+            if self.engine.debug && !self.sets[set].is_empty() {
+                eprintln!("[*] Earley set {set} has {} items", self.sets[set].len());
+            }
+        }
+    }
+
+    /// Advance the items that were waiting for the (completed) item
+    fn complete(&mut self, id: usize) {
+        let Item {
+            prod, origin, end, ..
+        } = self.items[id];
+        let lhs = self.grammar.prods[prod].lhs;
+
+        let mut idx = 0;
+        while idx < self.sets[origin].len() {
+            let wid = self.sets[origin][idx];
+            let waiting = self.items[wid];
+            idx += 1;
+
+            let symbols = &self.grammar.prods[waiting.prod].symbols;
+            if let Some(Symbol::NonTerminal(nt)) = symbols.get(waiting.dot) {
+                if *nt == lhs {
+                    let link = Link::Completed(wid, id);
+                    self.add(end, waiting.prod, waiting.dot + 1, waiting.origin, link);
+                }
+            }
+        }
+    }
+
+    /// Push the node of a completed item, if it is an anonymous non-terminal (a group or a
+    /// repetition), its children are pushed instead
+    fn nodes(&self, id: usize, outp: &mut Vec<Rc<Node>>) {
+        let item = &self.items[id];
+        let prod = &self.grammar.prods[item.prod];
+        let Some(name) = self.grammar.names[prod.lhs] else {
+            self.children(id, outp);
+            return;
+        };
+
+        let mut node = Node {
+            rule: name.to_string(),
+            variant: prod.variant,
+            start: item.origin,
+            end: item.end,
+            children: Vec::new(),
+        };
+        self.children(id, &mut node.children);
+        outp.push(Rc::new(node));
+    }
+
+    /// Push the nodes of the non-terminals before the dot of the item
+    /// The children of the anonymous non-terminals are expanded here, without recursing, as
+    /// a repetition (`P ::= P a`) is nested once per iteration and would overflow the stack
+    fn children(&self, id: usize, outp: &mut Vec<Rc<Node>>) {
+        // The links still to visit, the last one is the next
+        let mut pending = Vec::new();
+        self.push_links(id, &mut pending);
+
+        while let Some((prev, child)) = pending.pop() {
+            match child {
+                Some(child) => {
+                    let lhs = self.grammar.prods[self.items[child].prod].lhs;
+                    match self.grammar.names[lhs] {
+                        Some(_) => self.nodes(child, outp),
+                        None => self.push_links(child, &mut pending),
+                    }
+                }
+                None => {
+                    let prev = &self.items[prev];
+                    let Symbol::NonTerminal(nt) = self.grammar.prods[prev.prod].symbols[prev.dot]
+                    else {
+                        unreachable!("Only the non-terminals can be skipped");
+                    };
+                    self.null_nodes(nt, prev.end, outp);
+                }
+            }
+        }
+    }
+
+    /// Push the links to the non-terminals before the dot of the item (the previous item, and
+    /// the completed one, None if it was skipped), from the last symbol to the first one
+    fn push_links(&self, id: usize, outp: &mut Vec<(usize, Option<usize>)>) {
+        let mut cur = id;
+        loop {
+            match self.items[cur].link {
+                Link::Predicted => break,
+                Link::Scanned(prev) => cur = prev,
+                Link::Skipped(prev) => {
+                    outp.push((prev, None));
+                    cur = prev;
+                }
+                Link::Completed(prev, child) => {
+                    outp.push((prev, Some(child)));
+                    cur = prev;
+                }
+            }
+        }
+    }
+
+    /// Push the nodes of a non-terminal that matched the empty string at `offset`, just like
+    /// [`Chart::nodes`] does
+    fn null_nodes(&self, nt: usize, offset: usize, outp: &mut Vec<Rc<Node>>) {
+        let prod = self.grammar.null_prod[nt].expect("The non-terminal is nullable");
+
+        let mut children = Vec::new();
+        for sym in self.grammar.prods[prod].symbols.iter() {
+            if let Symbol::NonTerminal(sub) = sym {
+                self.null_nodes(*sub, offset, &mut children);
+            }
+        }
+
+        match self.grammar.names[nt] {
+            Some(name) => outp.push(Rc::new(Node {
+                rule: name.to_string(),
+                variant: self.grammar.prods[prod].variant,
+                start: offset,
+                end: offset,
+                children,
+            })),
+            None => outp.extend(children),
+        }
+    }
+}
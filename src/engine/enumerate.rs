@@ -0,0 +1,194 @@
+//! Exhaustive enumeration of the strings of the rules, see [`Enumerator`]
+
+use super::atoms::{Atoms, Part};
+use super::{Engine, EnumOptions};
+use crate::ast::{CharClass, Rule};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// A set of strings, together with their length (in characters) so they are sorted in
+/// shortlex order: the shorter ones first, and the ones with the same length in lexicographic
+/// order
+type Language = BTreeSet<(usize, String)>;
+
+/// Finds the strings of every rule (reachable from the requested one) as a fixed point: all of
+/// them start empty, and on each pass the strings of every variant are computed from the
+/// strings found so far, until nothing changes.
+///
+/// Every set only keeps the strings within the limits, and since the first N strings of a
+/// concatenation only use the first N strings of each part, this gives exactly the first N
+/// strings of the language
+pub(super) struct Enumerator<'e, 'o> {
+    /// The requested rule
+    start: &'e Rule,
+    /// The atoms of the rules that can be reached from the requested one
+    atoms: Atoms<'e>,
+    opts: &'o EnumOptions,
+    /// The strings found so far for each rule
+    langs: BTreeMap<&'e str, Language>,
+    /// The characters of each class (by the index of the atom), they are the same on every pass
+    classes: HashMap<usize, Language>,
+}
+
+impl<'e, 'o> Enumerator<'e, 'o> {
+    pub(super) fn new(engine: &'e Engine, start: &'e Rule, opts: &'o EnumOptions) -> Self {
+        let mut enumerator = Self {
+            start,
+            atoms: Atoms::new(&engine.reachable(&start.name)),
+            opts,
+            langs: BTreeMap::new(),
+            classes: HashMap::new(),
+        };
+
+        for (id, part) in enumerator.atoms.parts.iter().enumerate() {
+            if let Part::Class(class) = part {
+                enumerator.classes.insert(id, enumerator.class(class));
+            }
+        }
+
+        enumerator
+    }
+
+    /// The strings of the requested rule, in shortlex order
+    pub(super) fn run(mut self) -> Vec<String> {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (name, variants) in self.atoms.rules.iter() {
+                let lang = self.variants(variants);
+                if self.langs.get(name) != Some(&lang) {
+                    self.langs.insert(name, lang);
+                    changed = true;
+                }
+            }
+        }
+
+        self.langs
+            .remove(self.start.name.as_str())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(_, data)| data)
+            .collect()
+    }
+
+    /// Whether there is no room for more strings of the given length
+    fn too_long(&self, len: usize) -> bool {
+        self.opts.max_len.is_some_and(|max| len > max)
+    }
+
+    /// Add a string to the set, removing the last ones if there are too many
+    fn insert(&self, lang: &mut Language, data: String) {
+        let len = data.chars().count();
+        if self.too_long(len) {
+            return;
+        }
+
+        lang.insert((len, data));
+        if let Some(count) = self.opts.max_count {
+            while lang.len() > count {
+                lang.pop_last();
+            }
+        }
+    }
+
+    /// The strings of any of the variants
+    fn variants(&self, variants: &[Vec<usize>]) -> Language {
+        let mut lang = Language::new();
+        for items in variants {
+            for (_, data) in self.sequence(items) {
+                self.insert(&mut lang, data);
+            }
+        }
+        lang
+    }
+
+    /// The strings of all the atoms, one after the other
+    fn sequence(&self, items: &[usize]) -> Language {
+        let mut lang = Language::from([(0, String::new())]);
+        for id in items {
+            lang = self.concat(&lang, &self.atom(*id));
+            if lang.is_empty() {
+                break;
+            }
+        }
+        lang
+    }
+
+    /// Every string of `first` followed by every string of `second`
+    fn concat(&self, first: &Language, second: &Language) -> Language {
+        let mut lang = Language::new();
+        for (flen, fdata) in first {
+            for (slen, sdata) in second {
+                // The next ones are longer
+                if self.too_long(flen + slen) {
+                    break;
+                }
+
+                let data = format!("{fdata}{sdata}");
+                // The next ones come later too
+                let full = self.opts.max_count.is_some_and(|count| lang.len() >= count);
+                if full
+                    && lang
+                        .last()
+                        .is_some_and(|last| (flen + slen, &data) > (last.0, &last.1))
+                {
+                    break;
+                }
+                self.insert(&mut lang, data);
+            }
+        }
+        lang
+    }
+
+    /// The strings of a single atom
+    fn atom(&self, id: usize) -> Language {
+        match &self.atoms.parts[id] {
+            Part::Terminal(content) => {
+                let mut lang = Language::new();
+                self.insert(&mut lang, content.to_string());
+                lang
+            }
+            Part::Class(_) => self.classes[&id].clone(),
+            Part::NonTerminal(name) => self.langs.get(name).cloned().unwrap_or_default(),
+            Part::Optional(inner) => {
+                let mut lang = self.atom(*inner);
+                self.insert(&mut lang, String::new());
+                lang
+            }
+            Part::ZeroOrMore(inner) => self.star(&self.atom(*inner)),
+            Part::OneOrMore(inner) => {
+                let lang = self.atom(*inner);
+                self.concat(&lang, &self.star(&lang))
+            }
+            Part::Group(variants) => self.variants(variants),
+        }
+    }
+
+    /// Zero or more strings of the language (also a fixed point)
+    fn star(&self, lang: &Language) -> Language {
+        let mut star = Language::new();
+        self.insert(&mut star, String::new());
+        loop {
+            let mut next = self.concat(&star, lang);
+            self.insert(&mut next, String::new());
+            if next == star {
+                return star;
+            }
+            star = next;
+        }
+    }
+
+    /// The characters of the class
+    fn class(&self, class: &CharClass) -> Language {
+        let mut lang = Language::new();
+        if self.too_long(1) {
+            return lang;
+        }
+
+        // In order, so only the first ones are needed
+        let count = self.opts.max_count.unwrap_or(usize::MAX);
+        for c in class.chars().take(count) {
+            self.insert(&mut lang, c.to_string());
+        }
+        lang
+    }
+}
@@ -0,0 +1,247 @@
+//! Near-miss mutations of the generated strings, see [`Mutator`]
+
+use super::{Algorithm, Engine, GenOptions, GenerateError, MatchOptions, Sample, GEN_ATTEMPTS};
+use crate::ast::{Atom, Rule};
+use rand::{seq::SliceRandom, Rng};
+
+/// The ways of mutating a string
+#[derive(Debug, Clone, Copy)]
+enum Mutation {
+    /// Remove one of the terminals
+    Delete,
+    /// Swap two consecutive terminals
+    Swap,
+    /// Insert any terminal of the grammar between two terminals
+    Insert,
+    /// Replace the string of a rule by the string of another rule
+    Substitute,
+}
+
+const MUTATIONS: [Mutation; 4] = [
+    Mutation::Delete,
+    Mutation::Swap,
+    Mutation::Insert,
+    Mutation::Substitute,
+];
+
+/// Generates strings of a rule and mutates them (see [`Mutation`]) until one of them is
+/// rejected by the rule, the matching uses [`Algorithm::Earley`] so only the strings that the
+/// grammar cannot generate are rejected
+pub(super) struct Mutator<'e> {
+    engine: &'e Engine,
+    rule: &'e Rule,
+    /// The terminals and the classes of the grammar, to insert them
+    terminals: Vec<&'e Atom>,
+    /// The rules that can generate something, to substitute them
+    productive: Vec<&'e Rule>,
+}
+
+impl<'e> Mutator<'e> {
+    pub(super) fn new(engine: &'e Engine, rule: &'e Rule) -> Self {
+        let mut terminals = Vec::new();
+        for atom in engine
+            .tree
+            .values()
+            .flat_map(|rule| rule.variants.iter())
+            .flat_map(|variant| variant.items.iter())
+        {
+            atom.for_each_atom(&mut |atom| {
+                if let Atom::Terminal { .. } | Atom::CharClass { .. } = atom {
+                    terminals.push(atom);
+                }
+            });
+        }
+
+        let productive = engine
+            .tree
+            .values()
+            .filter(|rule| engine.min_height.contains_key(&rule.name))
+            .collect();
+
+        Self {
+            engine,
+            rule,
+            terminals,
+            productive,
+        }
+    }
+
+    /// Generate a mutated string that the rule rejects, up to [`GEN_ATTEMPTS`] mutations are
+    /// tried
+    pub(super) fn gen_negative<R: Rng + ?Sized>(
+        &self,
+        opts: &GenOptions,
+        rng: &mut R,
+    ) -> Result<String, GenerateError> {
+        let match_opts = MatchOptions {
+            full: true,
+            algorithm: Algorithm::Earley,
+            packrat: false,
+        };
+        let to_watch = [self.rule.name.clone()];
+
+        for _ in 0..GEN_ATTEMPTS {
+            let sample = self.engine.gen_sample(self.rule, opts, rng)?;
+            let Some(mutant) = self.mutate(&sample, opts, rng) else {
+                continue;
+            };
+
+            let res = self
+                .engine
+                .match_rule(&self.rule.name, &to_watch, &mutant, &match_opts);
+            if res.is_err() {
+                return Ok(mutant);
+            }
+        }
+
+        Err(GenerateError::NoNegative(
+            self.rule.name.clone(),
+            GEN_ATTEMPTS,
+        ))
+    }
+
+    /// Apply a random mutation to the sample, see [`Mutator::apply`]
+    fn mutate<R: Rng + ?Sized>(
+        &self,
+        sample: &Sample,
+        opts: &GenOptions,
+        rng: &mut R,
+    ) -> Option<String> {
+        let mutation = *MUTATIONS.choose(rng)?;
+        self.apply(mutation, sample, opts, rng)
+    }
+
+    /// Apply the mutation to the sample, None if it cannot be applied to it (like deleting a
+    /// terminal from an empty string)
+    fn apply<R: Rng + ?Sized>(
+        &self,
+        mutation: Mutation,
+        sample: &Sample,
+        opts: &GenOptions,
+        rng: &mut R,
+    ) -> Option<String> {
+        let data = &sample.data;
+        // The empty terminals cannot be deleted nor swapped
+        let terminals: Vec<_> = sample
+            .terminals
+            .iter()
+            .filter(|(start, end)| start < end)
+            .collect();
+
+        match mutation {
+            Mutation::Delete => {
+                let (start, end) = terminals.choose(rng)?;
+                Some(format!("{}{}", &data[..*start], &data[*end..]))
+            }
+            Mutation::Swap => {
+                if terminals.len() < 2 {
+                    return None;
+                }
+                let idx = rng.gen_range(0..terminals.len() - 1);
+                let ((fstart, fend), (sstart, send)) = (terminals[idx], terminals[idx + 1]);
+                Some(format!(
+                    "{}{}{}{}{}",
+                    &data[..*fstart],
+                    &data[*sstart..*send],
+                    &data[*fend..*sstart],
+                    &data[*fstart..*fend],
+                    &data[*send..]
+                ))
+            }
+            Mutation::Insert => {
+                // Between two terminals, or at the ends
+                let at = match terminals.choose(rng) {
+                    Some((start, end)) => **[start, end].choose(rng)?,
+                    None => 0,
+                };
+                let piece = match self.terminals.choose(rng)? {
+                    Atom::Terminal { content } => content.clone(),
+                    Atom::CharClass { class } => Engine::gen_random_char(class, rng).to_string(),
+                    _ => unreachable!("Only terminals and classes are inserted"),
+                };
+                Some(format!("{}{piece}{}", &data[..at], &data[at..]))
+            }
+            Mutation::Substitute => {
+                let (name, start, end) = sample.rules.choose(rng)?;
+                let other = self.productive.choose(rng)?;
+                if other.name == *name {
+                    return None;
+                }
+                // The length limits are for the whole string, not for the piece
+                let piece_opts = GenOptions {
+                    max_depth: opts.max_depth,
+                    max_nodes: opts.max_nodes,
+                    ..Default::default()
+                };
+                let piece = self.engine.gen_sample(other, &piece_opts, rng).ok()?;
+                Some(format!(
+                    "{}{}{}",
+                    &data[..*start],
+                    piece.data,
+                    &data[*end..]
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn negative_samples_are_rejected() {
+        let tree = ast::parse("<s> ::= \"a\" \"b\" | \"a\" <s> \"b\" | [0-9]+\n").unwrap();
+        let engine = Engine::build(&tree, false).unwrap();
+        let opts = MatchOptions {
+            full: true,
+            algorithm: Algorithm::Earley,
+            ..Default::default()
+        };
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let samples = engine.negative_samples("s", &GenOptions::default(), &mut rng);
+        for sample in samples.unwrap().take(50) {
+            let sample = sample.unwrap();
+            assert!(
+                engine.match_tree("s", &sample, &opts).is_err(),
+                "{sample:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_mutations_are_not_applied() {
+        let tree = ast::parse("<s> ::= \"a\" | \"a\" <s>\n").unwrap();
+        let engine = Engine::build(&tree, false).unwrap();
+        let mutator = Mutator::new(&engine, &engine.tree["s"]);
+        let opts = GenOptions::default();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        // Nothing to delete, swap or substitute
+        let empty = Sample::default();
+        for mutation in [Mutation::Delete, Mutation::Swap, Mutation::Substitute] {
+            let res = mutator.apply(mutation, &empty, &opts, &mut rng);
+            assert_eq!(res, None, "{mutation:?}");
+        }
+        let res = mutator.apply(Mutation::Insert, &empty, &opts, &mut rng);
+        assert_eq!(res.as_deref(), Some("a"));
+
+        // A single terminal cannot be swapped, and <s> is the only rule to substitute
+        let single = Sample {
+            data: "a".into(),
+            chars: 1,
+            terminals: vec![(0, 1)],
+            rules: vec![("s", 0, 1)],
+            ..Default::default()
+        };
+        for mutation in [Mutation::Swap, Mutation::Substitute] {
+            let res = mutator.apply(mutation, &single, &opts, &mut rng);
+            assert_eq!(res, None, "{mutation:?}");
+        }
+        let res = mutator.apply(Mutation::Delete, &single, &opts, &mut rng);
+        assert_eq!(res.as_deref(), Some(""));
+    }
+}
@@ -11,13 +11,27 @@ pub enum LexError {
 }
 
 /// A token is a simple abstraction over the input data
+/// Line comments (starting with `;`, `#` or `//`) and block comments (`/* ... */`) are skipped,
+/// doc comments (`///`) are kept only at the start of a line, so they can be attached to the
+/// following rule (anywhere else they are skipped like a line comment)
 #[derive(Logos, Debug)]
+#[logos(extras = usize)]
 #[logos(skip "[ \r\t]+")]
+#[logos(skip "[;#][^\n]*")]
+#[logos(skip "//([^/\n][^\n]*)?")]
 pub enum Tk<'a> {
     /// A newline character, por linenumber purposes
+    /// Block comments are skipped here too, the number of newlines inside of them is kept in
+    /// the extras so [`tokenize`] can keep the line numbers right (they are not rule
+    /// separators)
     #[token("\n")]
+    #[token("/*", block_comment)]
     Nl,
 
+    /// A doc comment (`/// ...`), without the leading slashes
+    #[regex("///[^\n]*")]
+    DocComment(&'a str),
+
     /// An identifier
     /// (Shall we allow dashes? (-) )
     #[regex("[a-zA-Z_][a-zA-Z_0-9]*")]
@@ -39,29 +53,100 @@ pub enum Tk<'a> {
     #[token("|")]
     Pipe,
 
+    /// Start of a group of inline variants
+    #[token("(")]
+    LParen,
+
+    /// End of a group of inline variants
+    #[token(")")]
+    RParen,
+
+    /// The previous atom is optional (may match zero or one times)
+    #[token("?")]
+    Question,
+
+    /// The previous atom may be repeated zero or more times
+    #[token("*")]
+    Star,
+
+    /// The previous atom may be repeated one or more times
+    #[token("+")]
+    Plus,
+
+    /// Separates the two ends of a character range, like in `"a".."z"`
+    #[token("..")]
+    DotDot,
+
+    /// A character class, like `[a-zA-Z_]`, the brackets are removed while tokenizing
+    #[regex("\\[(\\\\.|[^\\]\\\\\n])*\\]")]
+    Class(&'a str),
+
+    /// The weight of a variant when generating, like `@3`
+    #[regex("@[0-9]+", |lex| lex.slice()[1..].parse().ok())]
+    Weight(u32),
+
+    /// An explicit empty variant (`ε`), matches the empty string
+    #[token("ε")]
+    Epsilon,
+
     /// An actual terminal value
     #[regex("\"(\\\\.|[^\"])*\"")]
     Terminal(&'a str),
 }
 
+/// Skip the rest of a block comment (up to and including the `*/`), counting its newlines
+fn block_comment<'a>(lex: &mut logos::Lexer<'a, Tk<'a>>) -> Result<logos::Skip, ()> {
+    let end = lex.remainder().find("*/").ok_or(())?;
+    lex.bump(end + 2);
+    lex.extras += lex.slice().matches('\n').count();
+    Ok(logos::Skip)
+}
+
+/// The tokens of the input, along with the line where each one of them is
+#[derive(Debug)]
+pub struct Tokens<'a> {
+    pub tks: Vec<Tk<'a>>,
+    /// The line (starting at 1) of every token in `tks`
+    pub lines: Vec<usize>,
+}
+
 /// This makes the work of tokenize the input haystack
 /// This is a simple abstraction over the input data
-pub fn tokenize<'a>(data: &'a str) -> Result<Vec<Tk<'a>>, LexError> {
+pub fn tokenize<'a>(data: &'a str) -> Result<Tokens<'a>, LexError> {
     let mut lexer = Tk::lexer(data);
     let mut res = Vec::new();
+    let mut lines = Vec::new();
     let mut line = 0;
 
-    while let Some(tk) = lexer.next() {
+    loop {
+        let tk = lexer.next();
+        // The newlines of the block comments skipped before this token
+        line += std::mem::take(&mut lexer.extras);
+
+        let Some(tk) = tk else {
+            break;
+        };
         match tk {
             Ok(tk) => {
                 if let Tk::Terminal(data) = tk {
                     res.push(Tk::Terminal(&data[1..data.len() - 1]));
-                } else if let Tk::Nl = tk {
-                    res.push(tk);
-                    line += 1;
+                } else if let Tk::Class(data) = tk {
+                    res.push(Tk::Class(&data[1..data.len() - 1]));
+                } else if let Tk::DocComment(data) = tk {
+                    // After something else in the same line, it is just a comment
+                    if !matches!(res.last(), None | Some(Tk::Nl)) {
+                        continue;
+                    }
+                    let data = &data[3..];
+                    res.push(Tk::DocComment(data.strip_prefix(' ').unwrap_or(data)));
                 } else {
                     res.push(tk);
                 }
+
+                lines.push(line + 1);
+                if let Some(Tk::Nl) = res.last() {
+                    line += 1;
+                }
             }
             Err(_) => {
                 return Err(LexError::Unexpected(line, lexer.slice().to_string()));
@@ -69,5 +154,5 @@ pub fn tokenize<'a>(data: &'a str) -> Result<Vec<Tk<'a>>, LexError> {
         }
     }
 
-    Ok(res)
+    Ok(Tokens { tks: res, lines })
 }
@@ -3,12 +3,24 @@
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use std::{fs::read_to_string, path::absolute};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::HashSet,
+    fs::{create_dir_all, read_to_string, write},
+    io::Write,
+    path::absolute,
+};
 
 mod ast;
 mod engine;
 mod lex;
 
+/// With `--unique`, stop after generating this many repeated strings in a row
+const MAX_REPEATED: usize = 10000;
+
+/// With `--length`, only look for ambiguities if there are up to this many derivations
+const MAX_CHECKED: usize = 100000;
+
 /// What to do
 #[derive(Subcommand, Clone)]
 enum Action {
@@ -21,6 +33,14 @@ enum Action {
             default_value = "false"
         )]
         wide: bool,
+
+        #[arg(
+            short = 'D',
+            long = "docs",
+            help = "Only print the rule names and their doc comments",
+            default_value = "false"
+        )]
+        docs: bool,
     },
 
     #[command(name = "dump-lex")]
@@ -51,6 +71,211 @@ enum Action {
             help = "The rule name that you want to generate"
         )]
         rule_name: String,
+
+        #[arg(
+            short = 's',
+            long = "seed",
+            help = "The seed of the random generator, by default a random one (printed to stderr, so the run can be repeated)"
+        )]
+        seed: Option<u64>,
+
+        #[arg(
+            short = 'D',
+            long = "max-depth",
+            help = "After this many nested rules, end the string as soon as possible",
+            default_value_t = engine::DEFAULT_MAX_DEPTH
+        )]
+        max_depth: usize,
+
+        #[arg(
+            long = "max-nodes",
+            help = "After expanding this many rules, end the string as soon as possible",
+            default_value_t = engine::DEFAULT_MAX_NODES
+        )]
+        max_nodes: usize,
+
+        #[arg(
+            long = "min-length",
+            help = "The minimum length (in characters) of the generated string"
+        )]
+        min_len: Option<usize>,
+
+        #[arg(
+            long = "max-length",
+            help = "The maximum length (in characters) of the generated string"
+        )]
+        max_len: Option<usize>,
+
+        #[arg(
+            short = 'c',
+            long = "count",
+            help = "How many strings to generate",
+            default_value_t = 1
+        )]
+        count: usize,
+
+        #[arg(
+            short = 'u',
+            long = "unique",
+            help = "Dont repeat the generated strings",
+            default_value = "false"
+        )]
+        unique: bool,
+
+        #[arg(
+            short = 'o',
+            long = "output",
+            name = "dir",
+            help = "Write each string to its own file inside this directory, instead of stdout"
+        )]
+        output: Option<String>,
+
+        #[arg(
+            short = '0',
+            long = "null",
+            help = "Separate the strings with a NUL character instead of a newline",
+            default_value = "false"
+        )]
+        null: bool,
+
+        #[arg(
+            short = 'C',
+            long = "cover",
+            help = "Generate a few strings that use every variant of every reachable rule (ignores the count and the limits)",
+            default_value = "false"
+        )]
+        cover: bool,
+
+        #[arg(
+            short = 'N',
+            long = "negative",
+            help = "Generate strings that are almost valid but rejected by the rule (mutations of valid ones)",
+            default_value = "false"
+        )]
+        negative: bool,
+
+        #[arg(
+            short = 'L',
+            long = "length",
+            help = "Generate strings of exactly this length (in characters), chosen uniformly among all the derivations of that length, so if the rule is ambiguous the strings with more derivations are more likely (ignores the weights and the limits)",
+            conflicts_with = "negative"
+        )]
+        length: Option<usize>,
+    },
+
+    #[command(name = "enumerate")]
+    Enumerate {
+        #[arg(
+            short = 'd',
+            long = "debug",
+            help = "Enable debug output",
+            default_value = "false"
+        )]
+        debug: bool,
+
+        #[arg(
+            short = 'n',
+            long = "name",
+            name = "rule-name",
+            help = "The rule name that you want to enumerate"
+        )]
+        rule_name: String,
+
+        #[arg(
+            short = 'l',
+            long = "max-length",
+            help = "The maximum length (in characters) of the strings"
+        )]
+        max_len: Option<usize>,
+
+        #[arg(short = 'c', long = "count", help = "The maximum amount of strings")]
+        count: Option<usize>,
+    },
+
+    #[command(name = "count")]
+    Count {
+        #[arg(
+            short = 'd',
+            long = "debug",
+            help = "Enable debug output",
+            default_value = "false"
+        )]
+        debug: bool,
+
+        #[arg(
+            short = 'n',
+            long = "name",
+            name = "rule-name",
+            help = "The rule name that you want to count"
+        )]
+        rule_name: String,
+
+        #[arg(
+            short = 'l',
+            long = "max-length",
+            help = "The maximum length (in characters) of the strings"
+        )]
+        max_len: usize,
+
+        #[arg(
+            short = 's',
+            long = "strings",
+            help = "Also count the distinct strings (slow, all of them are enumerated), a length with more derivations than strings is ambiguous",
+            default_value = "false"
+        )]
+        strings: bool,
+    },
+
+    #[command(name = "search")]
+    Search {
+        #[arg(
+            short = 'd',
+            long = "debug",
+            help = "Enable debug output",
+            default_value = "false"
+        )]
+        debug: bool,
+
+        #[arg(
+            short = 'o',
+            long = "overlapping",
+            help = "Also report the occurrences that overlap with the previous ones",
+            default_value = "false"
+        )]
+        overlapping: bool,
+
+        #[arg(
+            short = 'a',
+            long = "algorithm",
+            help = "The matching algorithm, backtrack and earley find the longest match at each offset, descent the one it commits to",
+            value_enum,
+            default_value_t = Algorithm::Descent
+        )]
+        algorithm: Algorithm,
+
+        #[arg(
+            short = 'm',
+            long = "memoize",
+            help = "Remember the result of each rule at each offset (packrat parsing), only for the descent algorithm",
+            default_value = "false"
+        )]
+        memoize: bool,
+
+        #[arg(
+            short = 'f',
+            long = "file",
+            name = "file",
+            help = "The file where to search, by default read from stdin"
+        )]
+        file: Option<String>,
+
+        #[arg(
+            short = 'n',
+            long = "name",
+            name = "rule-name",
+            help = "The rule name that you want to search"
+        )]
+        rule_name: String,
     },
 
     #[command(name = "match")]
@@ -71,6 +296,39 @@ enum Action {
         )]
         no_pretty: bool,
 
+        #[arg(
+            short = 'T',
+            long = "tree",
+            help = "Print the whole parse tree instead of the watched rules",
+            default_value = "false"
+        )]
+        tree: bool,
+
+        #[arg(
+            short = 'F',
+            long = "full",
+            help = "The initial rule must match the whole input, not only a prefix of it",
+            default_value = "false"
+        )]
+        full: bool,
+
+        #[arg(
+            short = 'a',
+            long = "algorithm",
+            help = "The matching algorithm",
+            value_enum,
+            default_value_t = Algorithm::Descent
+        )]
+        algorithm: Algorithm,
+
+        #[arg(
+            short = 'm',
+            long = "memoize",
+            help = "Remember the result of each rule at each offset (packrat parsing), only for the descent algorithm",
+            default_value = "false"
+        )]
+        memoize: bool,
+
         #[arg(
             short = 'f',
             long = "file",
@@ -97,6 +355,29 @@ enum Action {
     },
 }
 
+/// The matching algorithms, as command line values, see [`engine::Algorithm`]
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Algorithm {
+    /// Recursive descent, fast but it may reject valid strings
+    Descent,
+
+    /// Full backtracking, exact but it may take exponential time
+    Backtrack,
+
+    /// Earley parser, exact in cubic time at most
+    Earley,
+}
+
+impl From<Algorithm> for engine::Algorithm {
+    fn from(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Descent => Self::Descent,
+            Algorithm::Backtrack => Self::Backtrack,
+            Algorithm::Earley => Self::Earley,
+        }
+    }
+}
+
 /// The command line arguments
 #[derive(Parser)]
 #[command(name = "Toy BNF")]
@@ -110,6 +391,45 @@ struct Args {
     action: Action,
 }
 
+/// Print a node of the parse tree and its children, indented by their depth
+fn show_tree(node: &engine::Node, content: &str, depth: usize) {
+    println!(
+        "{:indent$}<{}> (variant {}) {}..{}: {:?}",
+        "",
+        node.rule,
+        node.variant + 1,
+        node.start,
+        node.end,
+        &content[node.start..node.end],
+        indent = depth * 2
+    );
+    for child in node.children.iter() {
+        show_tree(child, content, depth + 1);
+    }
+}
+
+/// The strings of `--length` are chosen uniformly among the derivations, so warn if some of
+/// them have more than one (if there are too many to enumerate, say it was not checked)
+fn warn_ambiguous(engine: &engine::Engine, rule: &str, len: usize) -> Result<()> {
+    let derivations = engine.count_derivations(rule, len)?;
+    let total = derivations.iter().sum::<num_bigint::BigUint>();
+    if total > MAX_CHECKED.into() {
+        eprintln!(
+            "Warning: there are {total} derivations up to length {len}, too many to rule out that the rule is ambiguous (then the strings with more derivations would be more likely)"
+        );
+        return Ok(());
+    }
+
+    let strings = engine.count_strings(rule, len)?;
+    if derivations[len] > strings[len].into() {
+        eprintln!(
+            "Warning: the rule is ambiguous, there are {} derivations of length {len} but {} strings, the ones with more derivations are more likely",
+            derivations[len], strings[len]
+        );
+    }
+    Ok(())
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
@@ -119,25 +439,194 @@ fn main() -> Result<()> {
 
     match args.action {
         Action::DumpLex { wide } => {
-            let tokens = lex::tokenize(&bnf_file)?;
+            let tokens = lex::tokenize(&bnf_file)?.tks;
             if wide {
                 println!("Lex tokens: {tokens:#?}");
             } else {
                 println!("Lex tokens: {tokens:?}");
             }
         }
-        Action::DumpAst { wide } => {
+        Action::DumpAst { wide, docs } => {
             let tree = ast::parse(&bnf_file)?;
-            if wide {
+            if docs {
+                for rule in tree {
+                    println!("<{}>", rule.name);
+                    for line in rule.doc.iter().flat_map(|doc| doc.lines()) {
+                        println!("    {line}");
+                    }
+                }
+            } else if wide {
                 println!("Ast tree: {tree:#?}");
             } else {
                 println!("Ast tree: {tree:?}");
             }
         }
-        Action::Generate { rule_name, debug } => {
+        Action::Generate {
+            rule_name,
+            debug,
+            seed,
+            max_depth,
+            max_nodes,
+            min_len,
+            max_len,
+            count,
+            unique,
+            output,
+            null,
+            cover,
+            negative,
+            length,
+        } => {
+            let tree = ast::parse(&bnf_file)?;
+            let engine = engine::Engine::build(&tree, debug)?;
+
+            let seed = seed.unwrap_or_else(|| {
+                let seed = rand::thread_rng().gen();
+                eprintln!("Seed: {seed}");
+                seed
+            });
+            let mut rng = StdRng::seed_from_u64(seed);
+            let opts = engine::GenOptions {
+                max_depth: Some(max_depth),
+                max_nodes: Some(max_nodes),
+                min_len,
+                max_len,
+            };
+            let output = output.map(absolute).transpose()?;
+            if let Some(dir) = &output {
+                create_dir_all(dir)?;
+            }
+
+            let mut stdout = std::io::stdout().lock();
+            let mut emit = |idx: usize, sample: &str| match &output {
+                Some(dir) => write(dir.join(format!("{idx:06}.txt")), sample),
+                None => {
+                    stdout.write_all(sample.as_bytes())?;
+                    stdout.write_all(if null { b"\0" } else { b"\n" })
+                }
+            };
+
+            if cover {
+                let coverage = engine.cover(&rule_name, &mut rng)?;
+                for (idx, sample) in coverage.samples.iter().enumerate() {
+                    emit(idx, sample)?;
+                }
+                for (rule, group, variant) in coverage.uncovered {
+                    match group {
+                        Some(group) => eprintln!(
+                            "Uncovered: <{rule}> (group {}, variant {})",
+                            group + 1,
+                            variant + 1
+                        ),
+                        None => eprintln!("Uncovered: <{rule}> (variant {})", variant + 1),
+                    }
+                }
+                return Ok(());
+            }
+
+            let mut samples: Box<dyn Iterator<Item = _>> = if negative {
+                Box::new(engine.negative_samples(&rule_name, &opts, &mut rng)?)
+            } else if let Some(len) = length {
+                warn_ambiguous(&engine, &rule_name, len)?;
+                Box::new(engine.uniform_samples(&rule_name, len, &mut rng)?)
+            } else {
+                Box::new(engine.samples(&rule_name, &opts, &mut rng)?)
+            };
+            let mut seen = HashSet::new();
+            let mut repeated = 0;
+            let mut idx = 0;
+            while idx < count {
+                let sample = samples.next().expect("The samples never end")?;
+
+                if unique && !seen.insert(sample.clone()) {
+                    // The rule may not have that many different strings
+                    repeated += 1;
+                    if repeated == MAX_REPEATED {
+                        eprintln!("Only {idx} different strings were found, stopping");
+                        break;
+                    }
+                    continue;
+                }
+                repeated = 0;
+
+                emit(idx, &sample)?;
+                idx += 1;
+            }
+        }
+
+        Action::Enumerate {
+            rule_name,
+            max_len,
+            count,
+            debug,
+        } => {
             let tree = ast::parse(&bnf_file)?;
             let engine = engine::Engine::build(&tree, debug)?;
-            println!("{}", engine.gen_random(&rule_name)?);
+
+            let opts = engine::EnumOptions {
+                max_len,
+                max_count: count,
+            };
+            for data in engine.enumerate(&rule_name, &opts)? {
+                println!("{data}");
+            }
+        }
+
+        Action::Count {
+            rule_name,
+            max_len,
+            strings,
+            debug,
+        } => {
+            let tree = ast::parse(&bnf_file)?;
+            let engine = engine::Engine::build(&tree, debug)?;
+
+            let derivations = engine.count_derivations(&rule_name, max_len)?;
+            let strings = if strings {
+                Some(engine.count_strings(&rule_name, max_len)?)
+            } else {
+                None
+            };
+
+            for (len, count) in derivations.iter().enumerate() {
+                match &strings {
+                    None => println!("{len}: {count} derivations"),
+                    Some(strings) if *count > strings[len].into() => println!(
+                        "{len}: {count} derivations, {} strings (ambiguous)",
+                        strings[len]
+                    ),
+                    Some(strings) => {
+                        println!("{len}: {count} derivations, {} strings", strings[len])
+                    }
+                }
+            }
+        }
+
+        Action::Search {
+            file,
+            rule_name,
+            overlapping,
+            algorithm,
+            memoize,
+            debug,
+        } => {
+            let file = match file.as_deref() {
+                None | Some("-") => "/dev/stdin".into(),
+                Some(file) => absolute(file)?,
+            };
+            let content = read_to_string(file)?;
+            let tree = ast::parse(&bnf_file)?;
+            let engine = engine::Engine::build(&tree, debug)?;
+
+            let opts = engine::MatchOptions {
+                full: false,
+                algorithm: algorithm.into(),
+                packrat: memoize,
+            };
+            // Like `grep -n -o`
+            for found in engine.search(&rule_name, &content, &opts, overlapping)? {
+                println!("{}:{}", found.line, &content[found.start..found.end]);
+            }
         }
 
         Action::Match {
@@ -145,6 +634,10 @@ fn main() -> Result<()> {
             initial,
             rules,
             no_pretty,
+            tree: print_tree,
+            full,
+            algorithm,
+            memoize,
             debug,
         } => {
             let file = file.unwrap_or("/dev/stdin".into());
@@ -159,54 +652,110 @@ fn main() -> Result<()> {
             let tree = ast::parse(&bnf_file)?;
             let engine = engine::Engine::build(&tree, debug)?;
 
-            let rules = if rules.len() == 0 {
+            let rules = if rules.is_empty() {
                 vec![initial.clone()]
             } else {
                 rules
             };
 
-            let matches = engine.match_rule(&initial, &rules, &content)?;
+            let opts = engine::MatchOptions {
+                full,
+                algorithm: algorithm.into(),
+                packrat: memoize,
+            };
+
+            if print_tree {
+                let root = engine.match_tree(&initial, &content, &opts)?;
+                show_tree(&root, &content, 0);
+                return Ok(());
+            }
+
+            let matches = engine.match_rule(&initial, &rules, &content, &opts)?;
 
             if no_pretty {
-                for (start, end) in matches {
-                    println!("Match {start}..{end}: {}", &content[start..end]);
+                for m in matches {
+                    println!(
+                        "Match <{}> {}..{}: {}",
+                        m.rule,
+                        m.start,
+                        m.end,
+                        &content[m.start..m.end]
+                    );
                 }
             } else {
-                // The colors (Green, blue, yellow and red)
-                const COLORS: &[&str] = &["42;30", "44;30", "43;30", "41;30"];
-                let mut lastdep = 0;
+                // The colors (Green, blue, yellow, red, magenta and cyan), one for each rule
+                const COLORS: &[&str] = &["42;30", "44;30", "43;30", "41;30", "45;30", "46;30"];
+                let color_of = |rule: &str| {
+                    let idx = rules.iter().position(|r| r == rule).unwrap_or(0);
+                    COLORS[idx % COLORS.len()]
+                };
+                if rules.len() > COLORS.len() {
+                    eprintln!(
+                        "Warning: only {} colors for {} rules, some of them share a color (use --no-pretty to see their names)",
+                        COLORS.len(),
+                        rules.len()
+                    );
+                }
+
+                println!("Legend (each match is between brackets):");
+                for rule in rules.iter() {
+                    println!("  \x1b[{}m<{rule}>\x1b[0m", color_of(rule));
+                }
+
+                let mut lastcol = None;
                 let mut bld = String::with_capacity(content.len() * 5);
-                for (idx, c) in content.chars().enumerate() {
-                    // TODO: Optimize this
-                    let mut depth = 0;
-                    for (start, end) in matches.iter() {
-                        if start <= &idx && &idx < end {
-                            depth += 1;
+                let mut push = |bld: &mut String, color: Option<&'static str>, c: char| {
+                    if color != lastcol {
+                        lastcol = color;
+                        match color {
+                            // Set no color
+                            None => bld.push_str("\x1b[0m"),
+                            // Set the new color
+                            Some(newcol) => {
+                                bld.push_str("\x1b[");
+                                bld.push_str(newcol);
+                                bld.push('m');
+                            }
                         }
                     }
-                    if depth == lastdep {
-                        // Do nothing, just put a char
-                        bld.push(c);
-                        continue;
-                    }
-                    // Update the depth and color
-                    lastdep = depth;
-                    if depth == 0 {
-                        // Set no color
-                        bld.extend("\x1b[0m".chars());
-                    } else {
-                        // Set the new color
-                        let newcol = COLORS[(depth - 1) % COLORS.len()];
-                        bld.extend("\x1b[".chars());
-                        bld.extend(newcol.chars());
-                        bld.extend("m".chars());
+                    bld.push(c);
+                };
+
+                // The parents go before their children, so the matches that contain the
+                // current one are in the stack (the innermost at the top)
+                let mut open: Vec<&engine::Match> = Vec::new();
+                let mut next = matches.iter().peekable();
+                // The end of the content too, to close the last matches
+                for (idx, c) in content.char_indices().chain([(content.len(), '\0')]) {
+                    loop {
+                        // Close the matches that do not contain the next one
+                        let starts = next.peek().filter(|m| m.start == idx);
+                        let top = open.last().filter(|top| match starts {
+                            Some(m) => m.end > top.end,
+                            None => top.end == idx,
+                        });
+                        if let Some(top) = top {
+                            push(&mut bld, Some(color_of(&top.rule)), ']');
+                            open.pop();
+                            continue;
+                        }
+
+                        // And open the next one
+                        let Some(m) = next.next_if(|m| m.start == idx) else {
+                            break;
+                        };
+                        push(&mut bld, Some(color_of(&m.rule)), '[');
+                        open.push(m);
                     }
 
-                    bld.push(c);
+                    if idx < content.len() {
+                        let color = open.last().map(|m| color_of(&m.rule));
+                        push(&mut bld, color, c);
+                    }
                 }
                 // Clear the color if was not black
-                if lastdep != 0 {
-                    bld.extend("\x1b[0m".chars());
+                if lastcol.is_some() {
+                    bld.push_str("\x1b[0m");
                 }
 
                 println!("Matches:");